@@ -0,0 +1,80 @@
+//! `#[derive(HeapSize)]` for the `HeapSize` trait of multicache. The heap size of a
+//! struct or enum is the sum of the heap sizes of its fields, with every type
+//! parameter required to implement `HeapSize` as well.
+
+extern crate proc_macro;
+
+use proc_macro2::TokenStream;
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, parse_quote, Data, DeriveInput, Fields, GenericParam, Index};
+
+#[proc_macro_derive(HeapSize)]
+pub fn derive_heap_size(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+  let mut input = parse_macro_input!(input as DeriveInput);
+
+  for param in input.generics.params.iter_mut() {
+    if let GenericParam::Type(ref mut param) = *param {
+      param.bounds.push(parse_quote!(::multicache::HeapSize));
+    }
+  }
+
+  let name = &input.ident;
+  let body = heap_size_body(&input.data);
+  let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+  let expanded = quote! {
+    impl #impl_generics ::multicache::HeapSize for #name #ty_generics #where_clause {
+      fn heap_size(&self) -> usize {
+        #body
+      }
+    }
+  };
+  proc_macro::TokenStream::from(expanded)
+}
+
+fn heap_size_body(data: &Data) -> TokenStream {
+  match *data {
+    Data::Struct(ref data) => {
+      let fields = match data.fields {
+        Fields::Named(ref fields) => fields.named.iter().map(|field| {
+          let name = &field.ident;
+          quote!(::multicache::HeapSize::heap_size(&self.#name))
+        }).collect(),
+        Fields::Unnamed(ref fields) => (0..fields.unnamed.len()).map(|i| {
+          let index = Index::from(i);
+          quote!(::multicache::HeapSize::heap_size(&self.#index))
+        }).collect(),
+        Fields::Unit => Vec::new(),
+      };
+      quote!(0 #(+ #fields)*)
+    },
+    Data::Enum(ref data) => {
+      let arms = data.variants.iter().map(|variant| {
+        let name = &variant.ident;
+        match variant.fields {
+          Fields::Named(ref fields) => {
+            let names: Vec<_> = fields.named.iter().map(|field| &field.ident).collect();
+            quote! {
+              Self::#name { #(ref #names),* } => 0 #(+ ::multicache::HeapSize::heap_size(#names))*
+            }
+          },
+          Fields::Unnamed(ref fields) => {
+            let names: Vec<_> = (0..fields.unnamed.len()).map(|i| format_ident!("field{}", i)).collect();
+            quote! {
+              Self::#name(#(ref #names),*) => 0 #(+ ::multicache::HeapSize::heap_size(#names))*
+            }
+          },
+          Fields::Unit => quote!(Self::#name => 0),
+        }
+      });
+      quote! {
+        match *self {
+          #(#arms,)*
+        }
+      }
+    },
+    Data::Union(_) => {
+      syn::Error::new(proc_macro2::Span::call_site(), "HeapSize can't be derived for unions")
+        .to_compile_error()
+    },
+  }
+}
@@ -0,0 +1,67 @@
+//! Sources of time for the expiration features of the cache.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Where the cache gets the current time from
+pub trait Clock: Send + Sync {
+  fn now(&self) -> Instant;
+}
+
+/// The default clock, backed by `Instant::now`
+#[derive(Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+  fn now(&self) -> Instant {
+    Instant::now()
+  }
+}
+
+/// A clock that only moves when told to, for testing time based behavior without
+/// having to sleep
+#[derive(Debug)]
+pub struct MockClock {
+  now: Mutex<Instant>,
+}
+
+impl MockClock {
+  pub fn new() -> MockClock {
+    MockClock {
+      now: Mutex::new(Instant::now()),
+    }
+  }
+
+  /// Move the clock forward by a given amount of time
+  pub fn advance(&self, duration: Duration) {
+    *self.now.lock().unwrap() += duration;
+  }
+}
+
+impl Default for MockClock {
+  fn default() -> MockClock {
+    MockClock::new()
+  }
+}
+
+impl Clock for MockClock {
+  fn now(&self) -> Instant {
+    *self.now.lock().unwrap()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{Clock, MockClock};
+  use std::time::Duration;
+
+  #[test]
+  fn advances() {
+    let clock = MockClock::new();
+    let start = clock.now();
+
+    assert_eq!(clock.now(), start);
+    clock.advance(Duration::from_secs(5));
+    assert_eq!(clock.now(), start + Duration::from_secs(5));
+  }
+}
@@ -0,0 +1,209 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+use std::sync::{Arc, Condvar, Mutex};
+
+enum FlightState<V> {
+  Loading,
+  Done(Arc<V>),
+  Failed,
+}
+
+// A load of a single key that other threads can wait on
+pub(crate) struct Flight<V> {
+  state: Mutex<FlightState<V>>,
+  done: Condvar,
+}
+
+impl<V> Flight<V> {
+  // Block until the load finishes, None means it failed or found the key absent and
+  // the caller should look it up again
+  pub(crate) fn wait(&self) -> Option<Arc<V>> {
+    let mut state = self.state.lock().unwrap();
+    loop {
+      match *state {
+        FlightState::Loading => state = self.done.wait(state).unwrap(),
+        FlightState::Done(ref val) => return Some(val.clone()),
+        FlightState::Failed => return None,
+      }
+    }
+  }
+
+  fn finish(&self, new: FlightState<V>) {
+    let mut state = self.state.lock().unwrap();
+    if let FlightState::Loading = *state {
+      *state = new;
+    }
+    self.done.notify_all();
+  }
+}
+
+// The loads currently in progress by key
+pub(crate) struct Flights<K,V> {
+  map: Mutex<HashMap<K,Arc<Flight<V>>>>,
+}
+
+impl<K,V> fmt::Debug for Flights<K,V> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{{ {} loading }}", self.map.lock().unwrap().len())
+  }
+}
+
+pub(crate) enum Join<'a,K: Hash+Eq,V> {
+  // This thread has to do the load and then complete the guard
+  Leader(FlightGuard<'a,K,V>),
+  // Someone else is already doing it
+  Follower(Arc<Flight<V>>),
+}
+
+impl<K,V> Flights<K,V> {
+  pub(crate) fn new() -> Flights<K,V> {
+    Flights {
+      map: Mutex::new(HashMap::new()),
+    }
+  }
+}
+
+impl<K: Hash+Eq+Clone,V> Flights<K,V> {
+  pub(crate) fn join(&self, key: &K) -> Join<'_,K,V> {
+    let mut map = self.map.lock().unwrap();
+    if let Some(flight) = map.get(key) {
+      return Join::Follower(flight.clone())
+    }
+
+    let flight = Arc::new(Flight {
+      state: Mutex::new(FlightState::Loading),
+      done: Condvar::new(),
+    });
+    map.insert(key.clone(), flight.clone());
+    Join::Leader(FlightGuard {
+      flights: self,
+      key: key.clone(),
+      flight,
+    })
+  }
+}
+
+// Makes sure that whatever happens to the leader, including its loader panicking, the
+// waiters get woken up and the key can be loaded again
+pub(crate) struct FlightGuard<'a,K: Hash+Eq,V> {
+  flights: &'a Flights<K,V>,
+  key: K,
+  flight: Arc<Flight<V>>,
+}
+
+impl<K: Hash+Eq,V> FlightGuard<'_,K,V> {
+  pub(crate) fn complete(self, val: Arc<V>) {
+    self.flight.finish(FlightState::Done(val));
+  }
+}
+
+impl<K: Hash+Eq,V> Drop for FlightGuard<'_,K,V> {
+  fn drop(&mut self) {
+    let mut map = match self.flights.map.lock() {
+      Ok(map) => map,
+      Err(poisoned) => poisoned.into_inner(),
+    };
+    if map.get(&self.key).is_some_and(|flight| Arc::ptr_eq(flight, &self.flight)) {
+      map.remove(&self.key);
+    }
+    drop(map);
+    self.flight.finish(FlightState::Failed);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::{Loaded, MultiCache};
+  use std::panic::{self, AssertUnwindSafe};
+  use std::sync::{Arc, Barrier};
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use std::thread;
+  use std::time::Duration;
+
+  #[test]
+  fn loads_once() {
+    let cache = Arc::new(MultiCache::new(1000));
+    let loads = Arc::new(AtomicUsize::new(0));
+    let barrier = Arc::new(Barrier::new(8));
+
+    let threads: Vec<_> = (0..8).map(|_| {
+      let (cache, loads, barrier) = (cache.clone(), loads.clone(), barrier.clone());
+      thread::spawn(move || {
+        barrier.wait();
+        cache.get_or_insert_with(0, || {
+          loads.fetch_add(1, Ordering::SeqCst);
+          thread::sleep(Duration::from_millis(50));
+          (String::from("value"), 100)
+        })
+      })
+    }).collect();
+    let vals: Vec<_> = threads.into_iter().map(|t| t.join().unwrap().unwrap()).collect();
+
+    assert_eq!(loads.load(Ordering::SeqCst), 1);
+    for val in vals.iter() {
+      assert!(Arc::ptr_eq(val, &vals[0]));
+    }
+    assert_eq!(cache.get(&0), Some(Arc::new(String::from("value"))));
+  }
+
+  #[test]
+  fn panic_does_not_poison() {
+    let cache = MultiCache::new(1000);
+
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+      cache.get_or_insert_with(0, || -> (i32, usize) { panic!("loader failed") })
+    }));
+    assert!(result.is_err());
+
+    assert_eq!(cache.get_or_insert_with(0, || (1, 100)), Some(Arc::new(1)));
+  }
+
+  #[test]
+  fn errors_are_not_cached() {
+    let cache = MultiCache::new(1000);
+
+    assert_eq!(cache.try_get_or_insert_with(0, || Err::<(u32, usize), _>("not found")), Err("not found"));
+    assert!(!cache.contains_key(&0));
+    assert_eq!(cache.try_get_or_insert_with(0, || Ok::<_, &str>((1, 100))), Ok(Some(Arc::new(1))));
+    assert_eq!(cache.try_get_or_insert_with(0, || Err::<(u32, usize), _>("not called")), Ok(Some(Arc::new(1))));
+  }
+
+  #[test]
+  fn waiters_retry_after_error() {
+    let cache = Arc::new(MultiCache::new(1000));
+    let barrier = Arc::new(Barrier::new(2));
+
+    let leader = {
+      let (cache, barrier) = (cache.clone(), barrier.clone());
+      thread::spawn(move || {
+        cache.try_get_or_insert_with(0, || {
+          barrier.wait();
+          thread::sleep(Duration::from_millis(50));
+          Err::<(i32, usize), _>("failed")
+        })
+      })
+    };
+    barrier.wait();
+    let waiter = cache.try_get_or_insert_with(0, || Ok::<_, &str>((1, 100)));
+
+    assert_eq!(leader.join().unwrap(), Err("failed"));
+    assert_eq!(waiter, Ok(Some(Arc::new(1))));
+  }
+
+  #[test]
+  fn remembers_absent() {
+    let cache = MultiCache::<u32,u32>::new(1000);
+    let loads = AtomicUsize::new(0);
+    let loader = || {
+      loads.fetch_add(1, Ordering::SeqCst);
+      Loaded::Absent(10, Duration::from_secs(60))
+    };
+
+    assert_eq!(cache.get_or_insert_with(0, loader), None);
+    assert_eq!(cache.get_or_insert_with(0, loader), None);
+    assert_eq!(cache.try_get_or_insert_with(0, || Err::<(u32, usize), _>("not called")), Ok(None));
+    assert_eq!(loads.load(Ordering::SeqCst), 1);
+    assert_eq!(cache.totalsize(), 10);
+  }
+}
@@ -0,0 +1,220 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::fmt;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+use crate::{Loaded, MultiCache};
+use crate::policy::EvictionPolicy;
+
+// The async loads currently in progress by key. Waiters hold a receiver and the leader
+// the sender, so if the leader's future gets dropped or panics the channel closes and
+// the waiters know to try again.
+pub(crate) struct AsyncFlights<K,V> {
+  map: Mutex<HashMap<K,watch::Receiver<Option<Arc<V>>>>>,
+}
+
+impl<K,V> fmt::Debug for AsyncFlights<K,V> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{{ {} loading }}", self.map.lock().unwrap().len())
+  }
+}
+
+enum AsyncJoin<'a,K: Hash+Eq,V> {
+  Leader(AsyncFlightGuard<'a,K,V>),
+  Follower(watch::Receiver<Option<Arc<V>>>),
+}
+
+impl<K,V> AsyncFlights<K,V> {
+  pub(crate) fn new() -> AsyncFlights<K,V> {
+    AsyncFlights {
+      map: Mutex::new(HashMap::new()),
+    }
+  }
+}
+
+impl<K: Hash+Eq+Clone,V> AsyncFlights<K,V> {
+  fn join(&self, key: &K) -> AsyncJoin<'_,K,V> {
+    let mut map = self.map.lock().unwrap();
+    if let Some(receiver) = map.get(key) {
+      return AsyncJoin::Follower(receiver.clone())
+    }
+
+    let (sender, receiver) = watch::channel(None);
+    map.insert(key.clone(), receiver);
+    AsyncJoin::Leader(AsyncFlightGuard {
+      flights: self,
+      key: key.clone(),
+      sender,
+    })
+  }
+}
+
+struct AsyncFlightGuard<'a,K: Hash+Eq,V> {
+  flights: &'a AsyncFlights<K,V>,
+  key: K,
+  sender: watch::Sender<Option<Arc<V>>>,
+}
+
+impl<K: Hash+Eq,V> AsyncFlightGuard<'_,K,V> {
+  fn complete(self, val: Arc<V>) {
+    self.sender.send_replace(Some(val));
+  }
+}
+
+impl<K: Hash+Eq,V> Drop for AsyncFlightGuard<'_,K,V> {
+  fn drop(&mut self) {
+    let mut map = match self.flights.map.lock() {
+      Ok(map) => map,
+      Err(poisoned) => poisoned.into_inner(),
+    };
+    if map.get(&self.key).is_some_and(|receiver| receiver.same_channel(&self.sender.subscribe())) {
+      map.remove(&self.key);
+    }
+  }
+}
+
+// Wait for the leader to finish, None means it failed or found the key absent and the
+// caller should look it up again
+async fn wait<V>(mut receiver: watch::Receiver<Option<Arc<V>>>) -> Option<Arc<V>> {
+  loop {
+    if let Some(ref val) = *receiver.borrow_and_update() {
+      return Some(val.clone())
+    }
+    if receiver.changed().await.is_err() {
+      return receiver.borrow().clone()
+    }
+  }
+}
+
+impl<K,V,P> MultiCache<K,V,P> {
+  /// Async version of `get_or_insert_with` where the loader returns a future. Only one
+  /// task runs the loader for a given missing key while the others wait for its
+  /// result. The cache is never kept locked while waiting so this is safe to use from
+  /// any async runtime.
+  pub async fn get_or_insert_with_async<F,Fut,L>(&self, key: K, loader: F) -> Option<Arc<V>>
+  where K: Hash+Eq+Clone, P: EvictionPolicy<K>, F: FnOnce() -> Fut, Fut: Future<Output=L>, L: Into<Loaded<V>> {
+    let result = self.try_get_or_insert_with_async(key, || async {
+      Ok::<_, Infallible>(loader().await)
+    }).await;
+    match result {
+      Ok(val) => val,
+      Err(never) => match never {},
+    }
+  }
+
+  /// Async version of `try_get_or_insert_with`. If the task running the loader gets an
+  /// error, panics or is cancelled the ones waiting on it try loading the value
+  /// themselves.
+  pub async fn try_get_or_insert_with_async<F,Fut,L,E>(&self, key: K, loader: F) -> Result<Option<Arc<V>>,E>
+  where K: Hash+Eq+Clone, P: EvictionPolicy<K>, F: FnOnce() -> Fut, Fut: Future<Output=Result<L,E>>, L: Into<Loaded<V>> {
+    let mut loader = Some(loader);
+    // Only the first lookup counts towards the stats
+    let mut count = true;
+    loop {
+      if let Some(found) = self.cached(&key, count) {
+        return Ok(found)
+      }
+      count = false;
+
+      match self.async_flights.join(&key) {
+        AsyncJoin::Follower(receiver) => {
+          if let Some(val) = wait(receiver).await {
+            return Ok(Some(val))
+          }
+        },
+        AsyncJoin::Leader(guard) => {
+          // Someone else may have finished loading it in the meanwhile
+          if let Some(found) = self.cached(&key, false) {
+            if let Some(ref val) = found {
+              guard.complete(val.clone());
+            }
+            return Ok(found)
+          }
+
+          let load = (loader.take().unwrap())();
+          #[cfg(feature = "tracing")]
+          let load = tracing::Instrument::instrument(load, tracing::debug_span!("load", key = %self.key_name(&key)));
+          let loaded = load.await?.into();
+          let found = self.store(key, loaded);
+          // Without a value the waiters look the key up again and find it absent
+          if let Some(ref val) = found {
+            guard.complete(val.clone());
+          }
+          return Ok(found)
+        },
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::MultiCache;
+  use std::sync::Arc;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use std::time::Duration;
+
+  #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+  async fn loads_once() {
+    let cache = Arc::new(MultiCache::new(1000));
+    let loads = Arc::new(AtomicUsize::new(0));
+
+    let tasks: Vec<_> = (0..8).map(|_| {
+      let (cache, loads) = (cache.clone(), loads.clone());
+      tokio::spawn(async move {
+        cache.get_or_insert_with_async(0, || async {
+          loads.fetch_add(1, Ordering::SeqCst);
+          tokio::time::sleep(Duration::from_millis(50)).await;
+          (String::from("value"), 100)
+        }).await
+      })
+    }).collect();
+    let mut vals = Vec::new();
+    for task in tasks {
+      vals.push(task.await.unwrap().unwrap());
+    }
+
+    assert_eq!(loads.load(Ordering::SeqCst), 1);
+    for val in vals.iter() {
+      assert!(Arc::ptr_eq(val, &vals[0]));
+    }
+  }
+
+  #[tokio::test]
+  async fn cancelled_leader() {
+    let cache = Arc::new(MultiCache::new(1000));
+
+    let leader = {
+      let cache = cache.clone();
+      tokio::spawn(async move {
+        cache.get_or_insert_with_async(0, || async {
+          tokio::time::sleep(Duration::from_secs(3600)).await;
+          (0, 100)
+        }).await
+      })
+    };
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    let waiter = {
+      let cache = cache.clone();
+      tokio::spawn(async move {
+        cache.get_or_insert_with_async(0, || async { (1, 100) }).await
+      })
+    };
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    leader.abort();
+
+    assert_eq!(waiter.await.unwrap(), Some(Arc::new(1)));
+  }
+
+  #[tokio::test]
+  async fn errors_are_not_cached() {
+    let cache = MultiCache::new(1000);
+
+    let result = cache.try_get_or_insert_with_async(0, || async { Err::<(i32, usize), _>("not found") }).await;
+    assert_eq!(result, Err("not found"));
+    let result = cache.try_get_or_insert_with_async(0, || async { Ok::<_, &str>((1, 100)) }).await;
+    assert_eq!(result, Ok(Some(Arc::new(1))));
+  }
+}
@@ -0,0 +1,190 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::ffi::OsString;
+use std::mem;
+use std::path::PathBuf;
+use crate::Weigher;
+
+/// Types that can tell how many bytes they own on the heap, following all the way
+/// through their contents. It can be derived with `#[derive(HeapSize)]` from the
+/// `multicache-derive` crate, also reexported here with the `derive` feature, which
+/// adds up the heap sizes of all the fields. Caches created with
+/// `MultiCache::with_weigher(bytesize, HeapSizeWeigher)` use it to size the elements
+/// put in with `put_weighed`.
+///
+/// Collections count their allocated capacity times the size of their elements plus
+/// the heap size of each element. Hash maps and B-trees have bookkeeping overhead that
+/// isn't counted.
+pub trait HeapSize {
+  /// Bytes owned on the heap, not counting the size of the value itself
+  fn heap_size(&self) -> usize;
+
+  /// Bytes taken up by the value itself and everything it owns on the heap
+  fn total_size(&self) -> usize
+  where Self: Sized {
+    mem::size_of::<Self>() + self.heap_size()
+  }
+}
+
+macro_rules! no_heap {
+  ($($t:ty),*) => {
+    $(impl HeapSize for $t {
+      fn heap_size(&self) -> usize { 0 }
+    })*
+  }
+}
+
+no_heap!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64, bool, char, (), &'static str);
+
+impl HeapSize for String {
+  fn heap_size(&self) -> usize { self.capacity() }
+}
+
+impl HeapSize for OsString {
+  fn heap_size(&self) -> usize { self.capacity() }
+}
+
+impl HeapSize for PathBuf {
+  fn heap_size(&self) -> usize { self.capacity() }
+}
+
+impl HeapSize for Box<str> {
+  fn heap_size(&self) -> usize { self.len() }
+}
+
+impl<T: HeapSize> HeapSize for Box<T> {
+  fn heap_size(&self) -> usize { (**self).total_size() }
+}
+
+impl<T: HeapSize> HeapSize for Box<[T]> {
+  fn heap_size(&self) -> usize {
+    self.len() * mem::size_of::<T>() + self.iter().map(HeapSize::heap_size).sum::<usize>()
+  }
+}
+
+impl<T: HeapSize> HeapSize for Option<T> {
+  fn heap_size(&self) -> usize { self.as_ref().map_or(0, HeapSize::heap_size) }
+}
+
+impl<T: HeapSize, const N: usize> HeapSize for [T; N] {
+  fn heap_size(&self) -> usize { self.iter().map(HeapSize::heap_size).sum() }
+}
+
+impl<T: HeapSize> HeapSize for Vec<T> {
+  fn heap_size(&self) -> usize {
+    self.capacity() * mem::size_of::<T>() + self.iter().map(HeapSize::heap_size).sum::<usize>()
+  }
+}
+
+impl<T: HeapSize> HeapSize for VecDeque<T> {
+  fn heap_size(&self) -> usize {
+    self.capacity() * mem::size_of::<T>() + self.iter().map(HeapSize::heap_size).sum::<usize>()
+  }
+}
+
+impl<K: HeapSize, V: HeapSize, S> HeapSize for HashMap<K,V,S> {
+  fn heap_size(&self) -> usize {
+    self.capacity() * mem::size_of::<(K, V)>() +
+    self.iter().map(|(k, v)| k.heap_size() + v.heap_size()).sum::<usize>()
+  }
+}
+
+impl<T: HeapSize, S> HeapSize for HashSet<T,S> {
+  fn heap_size(&self) -> usize {
+    self.capacity() * mem::size_of::<T>() + self.iter().map(HeapSize::heap_size).sum::<usize>()
+  }
+}
+
+impl<K: HeapSize, V: HeapSize> HeapSize for BTreeMap<K,V> {
+  fn heap_size(&self) -> usize {
+    self.iter().map(|(k, v)| k.total_size() + v.total_size()).sum()
+  }
+}
+
+impl<T: HeapSize> HeapSize for BTreeSet<T> {
+  fn heap_size(&self) -> usize { self.iter().map(HeapSize::total_size).sum() }
+}
+
+macro_rules! tuple_heap {
+  ($(($($name:ident $index:tt),+)),*) => {
+    $(impl<$($name: HeapSize),+> HeapSize for ($($name,)+) {
+      fn heap_size(&self) -> usize { 0 $(+ self.$index.heap_size())+ }
+    })*
+  }
+}
+
+tuple_heap!((A 0), (A 0, B 1), (A 0, B 1, C 2), (A 0, B 1, C 2, D 3));
+
+/// Weighs elements by the total size of both their key and value using `HeapSize`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HeapSizeWeigher;
+
+impl<K: HeapSize, V: HeapSize> Weigher<K,V> for HeapSizeWeigher {
+  fn weigh(&self, key: &K, value: &V) -> usize {
+    key.total_size() + value.total_size()
+  }
+}
+
+/// Weighs elements by the total size of only their value using `HeapSize`, for keys
+/// that don't implement it or are small enough not to matter
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ValueSizeWeigher;
+
+impl<K, V: HeapSize> Weigher<K,V> for ValueSizeWeigher {
+  fn weigh(&self, _key: &K, value: &V) -> usize {
+    value.total_size()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::MultiCache;
+  use super::{HeapSize, HeapSizeWeigher};
+  use multicache_derive::HeapSize;
+  use std::mem;
+
+  #[derive(HeapSize)]
+  struct Image {
+    name: String,
+    pixels: Vec<u16>,
+    tags: Option<Vec<String>>,
+  }
+
+  #[derive(HeapSize)]
+  enum Thumb<T> {
+    Empty,
+    Pixels(Vec<T>),
+    Named { name: String },
+  }
+
+  #[test]
+  fn sizes_containers() {
+    let names = vec![String::with_capacity(10), String::with_capacity(20)];
+
+    assert_eq!(5u32.heap_size(), 0);
+    assert_eq!(names.heap_size(), 2 * mem::size_of::<String>() + 30);
+    assert_eq!(Some(Box::new(7u64)).heap_size(), 8);
+    assert_eq!((String::with_capacity(4), 1u8).heap_size(), 4);
+  }
+
+  #[test]
+  fn derives() {
+    let image = Image {
+      name: String::with_capacity(8),
+      pixels: Vec::with_capacity(100),
+      tags: Some(vec![String::with_capacity(3)]),
+    };
+    assert_eq!(image.heap_size(), 8 + 200 + mem::size_of::<String>() + 3);
+
+    assert_eq!(Thumb::<u8>::Empty.heap_size(), 0);
+    assert_eq!(Thumb::Pixels(Vec::<u32>::with_capacity(10)).heap_size(), 40);
+    assert_eq!(Thumb::<u8>::Named { name: String::with_capacity(5) }.heap_size(), 5);
+  }
+
+  #[test]
+  fn accounts_keys_and_values() {
+    let cache = MultiCache::with_weigher(1000, HeapSizeWeigher);
+
+    cache.put_weighed(String::with_capacity(10), Vec::<u8>::with_capacity(100)).unwrap();
+    assert_eq!(cache.totalsize(), mem::size_of::<String>() + 10 + mem::size_of::<Vec<u8>>() + 100);
+  }
+}
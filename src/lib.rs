@@ -40,134 +40,988 @@
 //!    assert_eq!(cache.get(&2), Some(Arc::new(2)));
 //!  }
 //! ```
+//!
+//! Evicting the least recently used elements is only the default. Other strategies
+//! can be picked with `MultiCache::with_policy` from the ones in the `policy` module
+//! or by implementing `EvictionPolicy`.
 
 extern crate linked_hash_map;
+// So the code from multicache-derive also works inside the crate
+extern crate self as multicache;
 use linked_hash_map::LinkedHashMap;
+use std::convert::Infallible;
 use std::hash::Hash;
-use std::sync::{Mutex, Arc};
+use std::sync::{RwLock, Arc};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 use std::fmt;
 
+pub mod clock;
+mod flight;
+#[cfg(feature = "async")]
+mod future;
+mod heapsize;
+mod loading;
+pub mod policy;
+mod pool;
+pub mod pressure;
+#[cfg(feature = "prometheus")]
+pub mod prometheus;
+mod sharded;
+mod stats;
+mod weigher;
+use clock::{Clock, SystemClock};
+use flight::{Flights, Join};
+use policy::{EvictionPolicy, Lru};
+use pool::PoolMember;
+pub use heapsize::{HeapSize, HeapSizeWeigher, ValueSizeWeigher};
+#[cfg(feature = "derive")]
+pub use multicache_derive::HeapSize;
+pub use loading::LoadingCache;
+pub use pool::{MemoryPool, PoolEviction};
+pub use sharded::ShardedMultiCache;
+pub use stats::CacheStats;
+use stats::StatsCounter;
+pub use weigher::Weigher;
+
+#[cfg(feature = "tracing")]
+type KeyFormatter<K> = Arc<dyn Fn(&K) -> String + Send + Sync>;
+
+// How keys show up in tracing events. Keys don't have to implement Debug so unless a
+// formatter was set a hash of them is used, which is the same for equal keys within a
+// process.
+#[cfg(feature = "tracing")]
+fn format_key<K: Hash>(formatter: Option<&KeyFormatter<K>>, key: &K) -> String {
+  use std::hash::Hasher;
+  match formatter {
+    Some(formatter) => formatter(key),
+    None => {
+      let mut hasher = std::collections::hash_map::DefaultHasher::new();
+      key.hash(&mut hasher);
+      format!("{:016x}", hasher.finish())
+    },
+  }
+}
+
+/// Why an element stopped being in the cache
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RemovalCause {
+  /// Removed by the policy to make room for other elements
+  Evicted,
+  /// Overwritten by a put with the same key
+  Replaced,
+  /// Removed by calling remove, or by a put of the same key that the oversize policy
+  /// kept out of the cache
+  Explicit,
+  /// Its time to live or idle timeout ran out
+  Expired,
+  /// Removed by calling clear
+  Cleared,
+}
+
+/// An element found in the cache and whether it's still fresh, or a marker for a key
+/// that is known not to exist
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Lookup<V> {
+  /// Within its fresh lifetime
+  Fresh(Arc<V>),
+  /// Past its fresh lifetime but not yet expired, it should be revalidated
+  Stale(Arc<V>),
+  /// Known not to exist, put in with `put_absent`
+  Absent,
+}
+
+/// What a loader found for a key. Loaders can also just return the value along with its
+/// bytesize as a tuple.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Loaded<V> {
+  /// The value along with its bytesize
+  Value(V, usize),
+  /// The key doesn't exist, which is remembered like `put_absent` does taking up a
+  /// bytesize until a given amount of time passes
+  Absent(usize, Duration),
+}
+
+impl<V> From<(V, usize)> for Loaded<V> {
+  fn from((val, bytes): (V, usize)) -> Loaded<V> {
+    Loaded::Value(val, bytes)
+  }
+}
+
+/// What to do with an element that is larger than the whole cache
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OversizePolicy {
+  /// Don't put it in, `try_put` returns an error
+  Reject,
+  /// Put it in after evicting everything else, keeping it as the only element. This is
+  /// the default.
+  AdmitAlone,
+  /// Don't put it in but hand it back from `try_put` as if it had been
+  Bypass,
+}
+
+/// Why a put failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PutError {
+  /// The element is larger than the whole cache and the oversize policy rejects those
+  TooLarge {
+    bytes: usize,
+    maxsize: usize,
+  },
+  /// `put_weighed` was called on a cache without a weigher
+  NoWeigher,
+}
+
+impl fmt::Display for PutError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      PutError::TooLarge { bytes, maxsize } =>
+        write!(f, "element of {} bytes is larger than the cache maxsize of {}", bytes, maxsize),
+      PutError::NoWeigher => write!(f, "cache has no weigher to work out the element's bytesize"),
+    }
+  }
+}
+
+impl std::error::Error for PutError {}
+
+type Listener<K,V> = Arc<dyn Fn(K, Arc<V>, usize, RemovalCause) + Send + Sync>;
+
 struct MultiCacheItem<V> {
-  val: V,
+  // None marks a key known to be absent
+  val: Option<V>,
   bytes: usize,
+  expires: Option<Instant>,
+  // When it stops being fresh, before it expires
+  stale: Option<Instant>,
+  written: Instant,
+  accessed: Instant,
+  // Position in the access order of the memory pool the cache is in, if any
+  used: AtomicU64,
 }
 
 impl<V> MultiCacheItem<V> {
-  pub fn new(val: Arc<V>, bytes: usize) -> MultiCacheItem<Arc<V>> {
+  pub fn new(val: Option<Arc<V>>, bytes: usize, expires: Option<Instant>, stale: Option<Instant>, now: Instant, used: u64) -> MultiCacheItem<Arc<V>> {
     MultiCacheItem {
-      val: val,
-      bytes: bytes,
+      val,
+      bytes,
+      expires,
+      stale,
+      written: now,
+      accessed: now,
+      used: AtomicU64::new(used),
     }
   }
+
+  fn expired(&self, now: Instant, idle: Option<Duration>) -> bool {
+    self.expires.is_some_and(|expires| now >= expires) ||
+    idle.is_some_and(|idle| now >= self.accessed + idle)
+  }
 }
 
-struct MultiCacheParts<K,V> {
+impl<V> MultiCacheItem<Arc<V>> {
+  fn lookup(&self, now: Instant) -> Lookup<V> {
+    match self.val {
+      None => Lookup::Absent,
+      Some(ref val) if self.stale.is_some_and(|stale| now >= stale) => Lookup::Stale(val.clone()),
+      Some(ref val) => Lookup::Fresh(val.clone()),
+    }
+  }
+}
+
+type Removed<K,V> = Vec<(K, MultiCacheItem<Arc<V>>, RemovalCause)>;
+
+struct MultiCacheParts<K,V,P> {
   hash: LinkedHashMap<K,MultiCacheItem<Arc<V>>>,
+  policy: P,
   totalsize: usize,
   maxsize: usize,
+  ttl: Option<Duration>,
+  idle: Option<Duration>,
+  clock: Arc<dyn Clock>,
+  listener: Option<Listener<K,V>>,
+  oversize: OversizePolicy,
+  weigher: Option<Arc<dyn Weigher<K,V>>>,
+  #[cfg(feature = "tracing")]
+  key_formatter: Option<KeyFormatter<K>>,
 }
 
-impl<K,V> fmt::Debug for MultiCacheParts<K,V> {
+impl<K,V,P> fmt::Debug for MultiCacheParts<K,V,P> {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
     write!(f, "{{ {} totalsize, {} maxsize }}",
       self.totalsize, self.maxsize)
   }
 }
 
-#[derive(Debug)]
-pub struct MultiCache<K,V> {
-  parts: Mutex<MultiCacheParts<K,V>>,
+impl<K,V,P> MultiCacheParts<K,V,P>
+where K: Hash+Eq, P: EvictionPolicy<K> {
+  fn remove(&mut self, key: &K) -> Option<(K, MultiCacheItem<Arc<V>>)> {
+    // Moving it to the back first is the only way to get the key out of the map along
+    // with the value
+    self.hash.get_refresh(key)?;
+    let (key, val) = self.hash.pop_back().unwrap();
+    self.totalsize -= val.bytes;
+    if !P::CACHE_ORDER {
+      self.policy.remove(&key);
+    }
+    Some((key, val))
+  }
+
+  fn insert(&mut self, key: K, val: MultiCacheItem<Arc<V>>) {
+    if !P::CACHE_ORDER {
+      self.policy.insert(&key, val.bytes);
+    }
+    self.totalsize += val.bytes;
+    self.hash.insert(key, val);
+  }
+
+  // Insert a value, giving back the one it replaced. The policy gets told it's an
+  // update so it can keep what it knows about the key.
+  fn replace(&mut self, key: K, val: MultiCacheItem<Arc<V>>) -> Option<(K, MultiCacheItem<Arc<V>>)> {
+    if self.hash.get_refresh(&key).is_none() {
+      self.insert(key, val);
+      return None
+    }
+    let (old_key, old) = self.hash.pop_back().unwrap();
+    self.totalsize -= old.bytes;
+    if !P::CACHE_ORDER {
+      self.policy.update(&key, val.bytes);
+    }
+    self.totalsize += val.bytes;
+    self.hash.insert(key, val);
+    Some((old_key, old))
+  }
+
+  // Get a value for a read, telling the policy about it
+  fn touch(&mut self, key: &K) -> Option<&mut MultiCacheItem<Arc<V>>> {
+    if P::CACHE_ORDER {
+      self.hash.get_refresh(key)
+    } else {
+      let val = self.hash.get_mut(key)?;
+      self.policy.touch(key);
+      Some(val)
+    }
+  }
+
+  // The key that would be evicted next
+  fn peek(&self) -> Option<&K> {
+    if P::CACHE_ORDER {
+      self.hash.front().map(|(key, _)| key)
+    } else {
+      self.policy.peek()
+    }
+  }
+
+  // Evict the next element picked by the policy
+  fn evict_one(&mut self, now: Instant) -> Option<(K, MultiCacheItem<Arc<V>>, RemovalCause)> {
+    let (key, val) = if P::CACHE_ORDER {
+      self.hash.pop_front()?
+    } else {
+      loop {
+        let key = self.policy.evict()?;
+        if self.hash.get_refresh(&key).is_some() {
+          break self.hash.pop_back().unwrap()
+        }
+      }
+    };
+    self.totalsize -= val.bytes;
+    let cause = if val.expired(now, self.idle) {
+      RemovalCause::Expired
+    } else {
+      RemovalCause::Evicted
+    };
+    Some((key, val, cause))
+  }
+
+  // Removes the value if it has expired, returning whether it was
+  fn remove_expired(&mut self, key: &K, now: Instant, removed: &mut Removed<K,V>) -> bool {
+    if self.hash.get(key).is_some_and(|val| val.expired(now, self.idle)) {
+      let (key, val) = self.remove(key).unwrap();
+      removed.push((key, val, RemovalCause::Expired));
+      return true
+    }
+    false
+  }
+
+  // Evict until we're within the byte budget, an item larger than the max will still
+  // be kept once it's the only one left
+  fn evict(&mut self, now: Instant, removed: &mut Removed<K,V>) {
+    while self.totalsize > self.maxsize && self.hash.len() > 1 {
+      match self.evict_one(now) {
+        None => break,
+        Some(evicted) => removed.push(evicted),
+      }
+    }
+  }
+}
+
+pub struct MultiCache<K,V,P=Lru<K>> {
+  parts: RwLock<MultiCacheParts<K,V,P>>,
+  pool: Option<Arc<MemoryPool>>,
+  flights: Flights<K,V>,
+  stats: StatsCounter,
+  #[cfg(feature = "async")]
+  async_flights: future::AsyncFlights<K,V>,
+}
+
+// Written out so that the policy doesn't have to implement Debug
+impl<K,V,P> fmt::Debug for MultiCache<K,V,P> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let mut cache = f.debug_struct("MultiCache");
+    cache.field("parts", &self.parts)
+      .field("pool", &self.pool)
+      .field("flights", &self.flights)
+      .field("stats", &self.stats);
+    #[cfg(feature = "async")]
+    cache.field("async_flights", &self.async_flights);
+    cache.finish()
+  }
 }
 
 impl<K,V> MultiCache<K,V> {
   /// Create a new cache which will at most hold a total of bytesize in elements
-  pub fn new(bytesize: usize) -> MultiCache<K,V> 
+  pub fn new(bytesize: usize) -> MultiCache<K,V>
   where K: Hash+Eq {
+    Self::with_policy(bytesize, Lru::new())
+  }
+
+  /// Create a new cache which will at most hold a total of bytesize in elements and
+  /// uses a weigher to work out the bytesize of elements put in with `put_weighed`
+  pub fn with_weigher<W>(bytesize: usize, weigher: W) -> MultiCache<K,V>
+  where K: Hash+Eq, W: Weigher<K,V> + 'static {
+    let cache = Self::new(bytesize);
+    cache.set_weigher(weigher);
+    cache
+  }
+
+  /// Create a new cache that takes up space from a memory pool shared with other
+  /// caches instead of having its own budget, with a given name and weight for the pool
+  /// to pick it for eviction
+  pub fn in_pool(pool: &Arc<MemoryPool>, name: &str, weight: u32) -> Arc<MultiCache<K,V>>
+  where K: Hash+Eq+Send+Sync+'static, V: Send+Sync+'static {
+    MultiCache::with_policy_in_pool(pool, name, weight, Lru::new())
+  }
+}
+
+impl<K,V,P> MultiCache<K,V,P> {
+  /// Create a new cache which will at most hold a total of bytesize in elements and
+  /// uses a given policy to decide what to evict
+  pub fn with_policy(bytesize: usize, policy: P) -> MultiCache<K,V,P>
+  where K: Hash+Eq, P: EvictionPolicy<K> {
+    Self::build(bytesize, policy, None)
+  }
+
+  /// Create a new cache that takes up space from a memory pool shared with other
+  /// caches and uses a given policy to decide what to evict
+  pub fn with_policy_in_pool(pool: &Arc<MemoryPool>, name: &str, weight: u32, policy: P) -> Arc<MultiCache<K,V,P>>
+  where K: Hash+Eq+Send+Sync+'static, V: Send+Sync+'static, P: EvictionPolicy<K>+Send+Sync+'static {
+    let cache = Arc::new(Self::build(pool.maxsize(), policy, Some(pool.clone())));
+    pool.attach(name, weight, Arc::downgrade(&cache) as std::sync::Weak<dyn PoolMember>);
+    cache
+  }
+
+  fn build(bytesize: usize, mut policy: P, pool: Option<Arc<MemoryPool>>) -> MultiCache<K,V,P>
+  where K: Hash+Eq, P: EvictionPolicy<K> {
+    policy.set_capacity(bytesize);
     MultiCache {
-      parts: Mutex::new(MultiCacheParts{
+      parts: RwLock::new(MultiCacheParts{
         hash: LinkedHashMap::new(),
+        policy,
         totalsize: 0,
         maxsize: bytesize,
+        ttl: None,
+        idle: None,
+        clock: Arc::new(SystemClock),
+        listener: None,
+        oversize: OversizePolicy::AdmitAlone,
+        weigher: None,
+        #[cfg(feature = "tracing")]
+        key_formatter: None,
       }),
+      pool,
+      flights: Flights::new(),
+      stats: StatsCounter::default(),
+      #[cfg(feature = "async")]
+      async_flights: future::AsyncFlights::new(),
+    }
+  }
+
+  /// Set the time to live for elements added from now on without an explicit one, None
+  /// means they never expire which is the default
+  pub fn set_default_ttl(&self, ttl: Option<Duration>) {
+    self.parts.write().unwrap().ttl = ttl;
+  }
+
+  /// Set how long elements can go without being read before they expire, None means
+  /// they never expire from not being used which is the default
+  pub fn set_idle_timeout(&self, idle: Option<Duration>) {
+    self.parts.write().unwrap().idle = idle;
+  }
+
+  /// Set where the current time gets read from for expiring elements, by default it's
+  /// the system clock
+  pub fn set_clock(&self, clock: Arc<dyn Clock>) {
+    self.parts.write().unwrap().clock = clock;
+  }
+
+  /// Set a function to be called with every element that stops being in the cache and
+  /// the reason why. It gets called after the cache is unlocked so it can use the cache
+  /// itself.
+  pub fn set_eviction_listener<F>(&self, listener: F)
+  where F: Fn(K, Arc<V>, usize, RemovalCause) + Send + Sync + 'static {
+    self.parts.write().unwrap().listener = Some(Arc::new(listener));
+  }
+
+  /// Set how to work out the bytesize of elements put in with `put_weighed`
+  pub fn set_weigher<W>(&self, weigher: W)
+  where W: Weigher<K,V> + 'static {
+    self.parts.write().unwrap().weigher = Some(Arc::new(weigher));
+  }
+
+  // The bytesize the weigher gives an element, None if the cache has no weigher
+  pub(crate) fn weigh(&self, key: &K, value: &V) -> Option<usize> {
+    self.parts.read().unwrap().weigher.as_ref().map(|weigher| weigher.weigh(key, value))
+  }
+
+  /// Set how keys show up in tracing events, for example with their Debug output. By
+  /// default a hash of the key is used, which is the same for equal keys.
+  #[cfg(feature = "tracing")]
+  pub fn set_key_formatter<F>(&self, formatter: F)
+  where F: Fn(&K) -> String + Send + Sync + 'static {
+    self.parts.write().unwrap().key_formatter = Some(Arc::new(formatter));
+  }
+
+  // How a key shows up in tracing events, can't be called with the cache locked
+  #[cfg(feature = "tracing")]
+  pub(crate) fn key_name(&self, key: &K) -> String
+  where K: Hash {
+    format_key(self.parts.read().unwrap().key_formatter.as_ref(), key)
+  }
+
+  /// Set what happens to elements larger than the whole cache when they're put in
+  pub fn set_oversize_policy(&self, policy: OversizePolicy) {
+    self.parts.write().unwrap().oversize = policy;
+  }
+
+  // Give back the space of removed elements to the pool and tell the listener about
+  // them, always called once the cache is unlocked
+  fn finish(&self, listener: Option<Listener<K,V>>, removed: Removed<K,V>)
+  where K: Hash {
+    for (_key, val, cause) in removed.iter() {
+      self.stats.removal(val.bytes, *cause);
+      #[cfg(feature = "tracing")]
+      if let RemovalCause::Evicted | RemovalCause::Expired = cause {
+        tracing::trace!(key = %self.key_name(_key), bytes = val.bytes, cause = ?cause, "removed from cache");
+      }
+    }
+    #[cfg(feature = "tracing")]
+    {
+      let evicted = removed.iter().filter(|(_, _, cause)| *cause == RemovalCause::Evicted);
+      let (count, bytes) = evicted.fold((0, 0), |(count, bytes), (_, val, _)| (count + 1, bytes + val.bytes));
+      if count > 0 {
+        tracing::debug!(count, bytes, "evicted from cache");
+      }
+    }
+    if let Some(ref pool) = self.pool {
+      pool.release(removed.iter().map(|(_, val, _)| val.bytes).sum());
+    }
+    if let Some(listener) = listener {
+      for (key, val, cause) in removed {
+        // Absent markers were never values so the listener doesn't hear about them
+        if let Some(value) = val.val {
+          listener(key, value, val.bytes, cause);
+        }
+      }
     }
   }
 
   /// Add a new element by key/value with a given bytesize, if after inserting this
   /// element we would be going over the bytesize of the cache first enough elements are
   /// evicted for that to not be the case
-  pub fn put(&self, key: K, value: V, bytes: usize) 
-  where K: Hash+Eq {
+  pub fn put(&self, key: K, value: V, bytes: usize)
+  where K: Hash+Eq, P: EvictionPolicy<K> {
     self.put_arc(key, Arc::new(value), bytes)
   }
 
   /// Add a new element by key/Arc<value> with a given bytesize, if after inserting this
   /// element we would be going over the bytesize of the cache first enough elements are
   /// evicted for that to not be the case
-  pub fn put_arc(&self, key: K, value: Arc<V>, bytes: usize) 
-  where K: Hash+Eq {
-    // First remove this key if it exists already, reclaiming that space
-    self.remove(&key);
+  pub fn put_arc(&self, key: K, value: Arc<V>, bytes: usize)
+  where K: Hash+Eq, P: EvictionPolicy<K> {
+    let _ = self.insert(key, Some(value), bytes, None, None, None);
+  }
 
-    let mut mparts = self.parts.lock().unwrap();
+  /// Add a new element by key/value with its bytesize worked out by the weigher set with
+  /// `with_weigher` or `set_weigher`. Without one nothing is put in and an error is
+  /// returned, same as for elements the oversize policy rejects like `try_put` does.
+  pub fn put_weighed(&self, key: K, value: V) -> Result<Arc<V>,PutError>
+  where K: Hash+Eq, P: EvictionPolicy<K> {
+    let bytes = self.weigh(&key, &value).ok_or(PutError::NoWeigher)?;
+    self.try_put(key, value, bytes)
+  }
 
-    // Now if we still need it reclaim more space
-    while mparts.totalsize + bytes > mparts.maxsize {
-      match mparts.hash.pop_front() {
-        None => break, // probably even the only item is larger than the max
-        Some(val) => {
-          mparts.totalsize -= val.1.bytes;
+  /// Add a new element by key/value with a given bytesize like `put` does, returning an
+  /// error if it's larger than the whole cache and the oversize policy rejects it. Any
+  /// older value for the key gets removed even then so it can't be served outdated.
+  pub fn try_put(&self, key: K, value: V, bytes: usize) -> Result<Arc<V>,PutError>
+  where K: Hash+Eq, P: EvictionPolicy<K> {
+    let value = Arc::new(value);
+    self.insert(key, Some(value.clone()), bytes, None, None, None)?;
+    Ok(value)
+  }
+
+  /// Add a new element by key/value with a given bytesize that will expire after a given
+  /// amount of time instead of the default for the cache
+  pub fn put_with_ttl(&self, key: K, value: V, bytes: usize, ttl: Duration)
+  where K: Hash+Eq, P: EvictionPolicy<K> {
+    let _ = self.insert(key, Some(Arc::new(value)), bytes, Some(ttl), None, None);
+  }
+
+  /// Remember that a key doesn't exist, taking up a given bytesize until a given amount
+  /// of time passes. `get` returns None for the key as if it wasn't in the cache while
+  /// `lookup` reports it as `Lookup::Absent`, so repeated lookups of missing keys don't
+  /// all have to go to wherever the values come from.
+  pub fn put_absent(&self, key: K, bytes: usize, ttl: Duration)
+  where K: Hash+Eq, P: EvictionPolicy<K> {
+    let _ = self.insert(key, None, bytes, Some(ttl), None, None);
+  }
+
+  /// Add a new element by key/value with a given bytesize that will be fresh for a given
+  /// amount of time and after that stale for another one before it expires. Stale
+  /// elements are still returned so they can keep being used while they're being
+  /// revalidated or if revalidating them fails, `lookup` tells them apart.
+  pub fn put_with_stale(&self, key: K, value: V, bytes: usize, fresh: Duration, stale: Duration)
+  where K: Hash+Eq, P: EvictionPolicy<K> {
+    let _ = self.insert(key, Some(Arc::new(value)), bytes, Some(fresh + stale), Some(fresh), None);
+  }
+
+  // Put in what a loader found, giving back the value if there was one
+  pub(crate) fn store(&self, key: K, loaded: Loaded<V>) -> Option<Arc<V>>
+  where K: Hash+Eq, P: EvictionPolicy<K> {
+    match loaded {
+      Loaded::Value(val, bytes) => {
+        let val = Arc::new(val);
+        self.put_arc(key, val.clone(), bytes);
+        Some(val)
+      },
+      Loaded::Absent(bytes, ttl) => {
+        self.put_absent(key, bytes, ttl);
+        None
+      },
+    }
+  }
+
+  // Put in what was loaded to replace the current value, unless the current one was
+  // removed or replaced in the meanwhile
+  pub(crate) fn replace(&self, key: K, current: &Arc<V>, loaded: Loaded<V>)
+  where K: Hash+Eq, P: EvictionPolicy<K> {
+    let _ = match loaded {
+      Loaded::Value(val, bytes) => self.insert(key, Some(Arc::new(val)), bytes, None, None, Some(current)),
+      Loaded::Absent(bytes, ttl) => self.insert(key, None, bytes, Some(ttl), None, Some(current)),
+    };
+  }
+
+  fn insert(&self, key: K, value: Option<Arc<V>>, bytes: usize, ttl: Option<Duration>, fresh: Option<Duration>, current: Option<&Arc<V>>) -> Result<(),PutError>
+  where K: Hash+Eq, P: EvictionPolicy<K> {
+    #[cfg(feature = "tracing")]
+    let _span = tracing::debug_span!("put", key = %self.key_name(&key), bytes).entered();
+    let mut removed = Vec::new();
+    let (listener, admitted) = {
+      let mparts = &mut *self.parts.write().unwrap();
+      let now = mparts.clock.now();
+
+      if let Some(current) = current {
+        let val = mparts.hash.get(&key).and_then(|val| val.val.as_ref());
+        if !val.is_some_and(|val| Arc::ptr_eq(val, current)) {
+          return Ok(())
+        }
+      }
+
+      // An expired value for this key is gone whatever happens next
+      mparts.remove_expired(&key, now, &mut removed);
+
+      #[cfg(feature = "tracing")]
+      if bytes > mparts.maxsize {
+        tracing::warn!(key = %format_key(mparts.key_formatter.as_ref(), &key), bytes, maxsize = mparts.maxsize, "element larger than the cache");
+      }
+
+      // Elements larger than the whole cache only go in if the policy lets them
+      let admitted = match mparts.oversize {
+        _ if bytes <= mparts.maxsize => Ok(true),
+        OversizePolicy::AdmitAlone => Ok(true),
+        OversizePolicy::Bypass => Ok(false),
+        OversizePolicy::Reject => Err(PutError::TooLarge { bytes, maxsize: mparts.maxsize }),
+      };
+      if admitted == Ok(true) {
+        // Save the value and take up the space
+        let expires = ttl.or(mparts.ttl).map(|ttl| now + ttl);
+        let stale = fresh.map(|fresh| now + fresh);
+        let used = self.pool.as_ref().map_or(0, |pool| pool.tick());
+        if let Some((key, val)) = mparts.replace(key, MultiCacheItem::new(value,bytes,expires,stale,now,used)) {
+          removed.push((key, val, RemovalCause::Replaced));
         }
+        // Charged while still locked so that removing the element from another thread
+        // can't release its space from the pool before it was taken
+        if let Some(ref pool) = self.pool {
+          pool.charge(bytes);
+        }
+
+        // Now if we need it reclaim space
+        mparts.evict(now, &mut removed);
+        self.stats.insert(mparts.totalsize);
+      } else if let Some((key, val)) = mparts.remove(&key) {
+        // The older value can't be served outdated, nothing replaced it though
+        removed.push((key, val, RemovalCause::Explicit));
+      }
+      (mparts.listener.clone(), admitted)
+    };
+
+    self.finish(listener, removed);
+    if let Some(ref pool) = self.pool {
+      pool.reclaim();
+    }
+    admitted.map(|_| ())
+  }
+
+  /// Get an element from the cache or if it's not there add the one returned by the
+  /// loader along with its bytesize. When several threads ask for the same missing key
+  /// at once only one of them runs its loader and the others wait for its result.
+  ///
+  /// The loader can also return `Loaded::Absent` to have the cache remember that the
+  /// key doesn't exist. None is then returned for it without running any loader until
+  /// that runs out, same as for keys put in with `put_absent`.
+  pub fn get_or_insert_with<F,L>(&self, key: K, loader: F) -> Option<Arc<V>>
+  where K: Hash+Eq+Clone, P: EvictionPolicy<K>, F: FnOnce() -> L, L: Into<Loaded<V>> {
+    match self.try_get_or_insert_with(key, || Ok::<_, Infallible>(loader())) {
+      Ok(val) => val,
+      Err(never) => match never {},
+    }
+  }
+
+  /// Like `get_or_insert_with` but with a loader that can fail. The error only goes to
+  /// the thread that ran the loader, the ones that were waiting on it then try loading
+  /// the value themselves. Errors and panics are never cached.
+  pub fn try_get_or_insert_with<F,L,E>(&self, key: K, loader: F) -> Result<Option<Arc<V>>,E>
+  where K: Hash+Eq+Clone, P: EvictionPolicy<K>, F: FnOnce() -> Result<L,E>, L: Into<Loaded<V>> {
+    self.load(key, true, loader)
+  }
+
+  // Only the first lookup of the key counts towards the stats, and only if count is set,
+  // so a single call is never more than one hit or miss
+  pub(crate) fn load<F,L,E>(&self, key: K, mut count: bool, loader: F) -> Result<Option<Arc<V>>,E>
+  where K: Hash+Eq+Clone, P: EvictionPolicy<K>, F: FnOnce() -> Result<L,E>, L: Into<Loaded<V>> {
+    let mut loader = Some(loader);
+    loop {
+      if let Some(found) = self.cached(&key, count) {
+        return Ok(found)
+      }
+      count = false;
+
+      match self.flights.join(&key) {
+        Join::Follower(flight) => {
+          if let Some(val) = flight.wait() {
+            return Ok(Some(val))
+          }
+        },
+        Join::Leader(guard) => {
+          // Someone else may have finished loading it in the meanwhile
+          if let Some(found) = self.cached(&key, false) {
+            if let Some(ref val) = found {
+              guard.complete(val.clone());
+            }
+            return Ok(found)
+          }
+
+          #[cfg(feature = "tracing")]
+          let _span = tracing::debug_span!("load", key = %self.key_name(&key)).entered();
+          let loaded = (loader.take().unwrap())()?.into();
+          let found = self.store(key, loaded);
+          // Without a value the waiters look the key up again and find it absent
+          if let Some(ref val) = found {
+            guard.complete(val.clone());
+          }
+          return Ok(found)
+        },
       }
     }
+  }
 
-    // Finally save the value and take up the space
-    (*mparts).hash.insert(key, MultiCacheItem::new(value,bytes));
-    mparts.totalsize += bytes;
+  // What the loading paths find in the cache for a key, with Some(None) for keys that
+  // are known to be absent
+  pub(crate) fn cached(&self, key: &K, count: bool) -> Option<Option<Arc<V>>>
+  where K: Hash+Eq, P: EvictionPolicy<K> {
+    self.read(key, count, |val, _| val.val.clone())
   }
 
   /// Get an element from the cache, updating it so it's now the most recently used and
   /// thus the last to be evicted
   pub fn get(&self, key: &K) -> Option<Arc<V>>
-  where K: Hash+Eq {
-    let mparts = &mut *(self.parts.lock().unwrap());
+  where K: Hash+Eq, P: EvictionPolicy<K> {
+    self.read(key, true, |val, _| val.val.clone()).flatten()
+  }
+
+  /// Get an element from the cache like `get` does, telling whether it's still fresh or
+  /// has gone stale and should be revalidated, or whether the key is known to be absent
+  pub fn lookup(&self, key: &K) -> Option<Lookup<V>>
+  where K: Hash+Eq, P: EvictionPolicy<K> {
+    self.read(key, true, |val, now| val.lookup(now))
+  }
+
+  // Same as get but also returns how long ago the element was put in, only counting
+  // towards the stats if count is set
+  pub(crate) fn get_aged(&self, key: &K, count: bool) -> Option<(Arc<V>, Duration)>
+  where K: Hash+Eq, P: EvictionPolicy<K> {
+    self.read(key, count, |val, now| {
+      val.val.clone().map(|value| (value, now.saturating_duration_since(val.written)))
+    }).flatten()
+  }
 
-    if let Some(val) = mparts.hash.get_refresh(key) {
-      return Some(val.val.clone())
+  // Record an access to an element, removing it if it expired, and take what's needed
+  // from it with the current time. When count is set it's a hit if there was a value
+  // and a miss otherwise, absent markers included.
+  fn read<F,R>(&self, key: &K, count: bool, f: F) -> Option<R>
+  where K: Hash+Eq, P: EvictionPolicy<K>, F: FnOnce(&MultiCacheItem<Arc<V>>, Instant) -> R {
+    if P::SHARED_HITS {
+      let mparts = self.parts.read().unwrap();
+      let now = mparts.clock.now();
+      match mparts.hash.get(key) {
+        None => {
+          if count {
+            self.stats.read(false);
+          }
+          return None
+        },
+        Some(val) if mparts.idle.is_none() && !val.expired(now, None) => {
+          if let Some(ref pool) = self.pool {
+            val.used.store(pool.tick(), Ordering::Relaxed);
+          }
+          mparts.policy.touch_shared(key);
+          if count {
+            self.stats.read(val.val.is_some());
+          }
+          return Some(f(val, now))
+        },
+        // Removing the expired value or recording the access time needs the exclusive
+        // lock
+        Some(_) => {},
+      }
     }
 
-    None
+    let mut removed = Vec::new();
+    let mut found = false;
+    let (val, listener) = {
+      let mparts = &mut *self.parts.write().unwrap();
+      let now = mparts.clock.now();
+      let idle = mparts.idle;
+      let val = match mparts.touch(key) {
+        None => None,
+        Some(val) if val.expired(now, idle) => {
+          let (key, val) = mparts.remove(key).unwrap();
+          removed.push((key, val, RemovalCause::Expired));
+          None
+        },
+        Some(val) => {
+          val.accessed = now;
+          if let Some(ref pool) = self.pool {
+            val.used.store(pool.tick(), Ordering::Relaxed);
+          }
+          found = val.val.is_some();
+          Some(f(val, now))
+        },
+      };
+      (val, mparts.listener.clone())
+    };
+
+    if count {
+      self.stats.read(found);
+    }
+    self.finish(listener, removed);
+    val
   }
 
   /// Remove an element from the cache, returning it if it exists
   pub fn remove(&self, key: &K) -> Option<Arc<V>>
-  where K: Hash+Eq {
-    let mut mparts = self.parts.lock().unwrap();
+  where K: Hash+Eq, P: EvictionPolicy<K> {
+    let (key, val, cause, listener) = {
+      let mut mparts = self.parts.write().unwrap();
+      let (key, val) = mparts.remove(key)?;
+      let cause = if val.expired(mparts.clock.now(), mparts.idle) {
+        RemovalCause::Expired
+      } else {
+        RemovalCause::Explicit
+      };
+      (key, val, cause, mparts.listener.clone())
+    };
 
-    // First remove this key if it exists already, reclaiming that space
-    if let Some(val) = (*mparts).hash.remove(&key) {
-      mparts.totalsize -= val.bytes;
-      Some(val.val)
-    } else {
-      None
-    }
+    let ret = if cause == RemovalCause::Explicit { val.val.clone() } else { None };
+    self.finish(listener, vec![(key, val, cause)]);
+    ret
   }
 
-  /// Check if a given key exists in the cache
+  /// Check if a given key exists in the cache, keys marked as absent don't
   pub fn contains_key(&self, key: &K) -> bool
-  where K: Hash+Eq {
-    let mparts = self.parts.lock().unwrap();
-    if (*mparts).hash.contains_key(&key) {
-      return true
+  where K: Hash+Eq, P: EvictionPolicy<K> {
+    {
+      let mparts = self.parts.read().unwrap();
+      match mparts.hash.get(key) {
+        None => return false,
+        Some(val) if !val.expired(mparts.clock.now(), mparts.idle) => return val.val.is_some(),
+        Some(_) => {},
+      }
     }
 
-    false
+    let mut removed = Vec::new();
+    let (exists, listener) = {
+      let mut mparts = self.parts.write().unwrap();
+      let now = mparts.clock.now();
+      mparts.remove_expired(key, now, &mut removed);
+      (mparts.hash.get(key).is_some_and(|val| val.val.is_some()), mparts.listener.clone())
+    };
+
+    self.finish(listener, removed);
+    exists
+  }
+
+  /// Remove all the elements that have expired or gone unused for longer than the idle
+  /// timeout, freeing up their space
+  pub fn purge_expired(&self)
+  where K: Hash+Eq+Clone, P: EvictionPolicy<K> {
+    let mut removed = Vec::new();
+    let listener = {
+      let mparts = &mut *self.parts.write().unwrap();
+      let now = mparts.clock.now();
+
+      let expired: Vec<K> = mparts.hash.iter()
+        .filter(|(_, val)| val.expired(now, mparts.idle))
+        .map(|(key, _)| key.clone())
+        .collect();
+      for key in expired {
+        mparts.remove_expired(&key, now, &mut removed);
+      }
+      mparts.listener.clone()
+    };
+
+    self.finish(listener, removed);
+  }
+
+  /// Remove all the elements from the cache
+  pub fn clear(&self)
+  where K: Hash+Eq, P: EvictionPolicy<K> {
+    let (removed, listener) = {
+      let mparts = &mut *self.parts.write().unwrap();
+      let removed: Removed<K,V> = mparts.hash.drain()
+        .map(|(key, val)| (key, val, RemovalCause::Cleared))
+        .collect();
+      if !P::CACHE_ORDER {
+        for (key, _, _) in removed.iter() {
+          mparts.policy.remove(key);
+        }
+      }
+      mparts.totalsize = 0;
+      (removed, mparts.listener.clone())
+    };
+
+    self.finish(listener, removed);
+  }
+
+  /// How many hits, misses, evictions and so on there have been in the cache
+  pub fn stats(&self) -> CacheStats {
+    self.stats.snapshot()
+  }
+
+  /// Start counting stats over from zero
+  pub fn reset_stats(&self) {
+    let mparts = self.parts.read().unwrap();
+    self.stats.reset(mparts.totalsize);
+  }
+
+  /// How many elements are in the cache, including expired ones not removed yet
+  pub fn len(&self) -> usize
+  where K: Hash+Eq {
+    self.parts.read().unwrap().hash.len()
+  }
+
+  /// Whether there are no elements in the cache
+  pub fn is_empty(&self) -> bool
+  where K: Hash+Eq {
+    self.len() == 0
+  }
+
+  /// How many bytes the elements in the cache take up
+  pub fn totalsize(&self) -> usize {
+    self.parts.read().unwrap().totalsize
+  }
+
+  /// How many bytes the cache can hold
+  pub fn maxsize(&self) -> usize {
+    self.parts.read().unwrap().maxsize
+  }
+
+  /// Change how many bytes the cache can hold. If it shrunk elements get evicted right
+  /// away until the cache fits, going through the eviction listener like any other
+  /// eviction.
+  pub fn set_max_bytes(&self, bytesize: usize)
+  where K: Hash+Eq, P: EvictionPolicy<K> {
+    let mut removed = Vec::new();
+    let listener = {
+      let mparts = &mut *self.parts.write().unwrap();
+      mparts.maxsize = bytesize;
+      mparts.policy.set_capacity(bytesize);
+      let now = mparts.clock.now();
+      mparts.evict(now, &mut removed);
+      mparts.listener.clone()
+    };
+
+    self.finish(listener, removed);
+  }
+}
+
+impl<K,V,P> PoolMember for MultiCache<K,V,P>
+where K: Hash+Eq+Send+Sync, V: Send+Sync, P: EvictionPolicy<K>+Send+Sync {
+  fn usage(&self) -> usize {
+    self.parts.read().unwrap().totalsize
+  }
+
+  fn next_victim(&self) -> Option<u64> {
+    let mparts = self.parts.read().unwrap();
+    let key = mparts.peek()?;
+    mparts.hash.get(key).map(|val| val.used.load(Ordering::Relaxed))
+  }
+
+  fn evict_one(&self) -> bool {
+    let mut removed = Vec::new();
+    let listener = {
+      let mparts = &mut *self.parts.write().unwrap();
+      let now = mparts.clock.now();
+      removed.extend(mparts.evict_one(now));
+      mparts.listener.clone()
+    };
+
+    let evicted = !removed.is_empty();
+    self.finish(listener, removed);
+    evicted
+  }
+}
+
+impl<K,V,P> Drop for MultiCache<K,V,P> {
+  fn drop(&mut self) {
+    if let Some(ref pool) = self.pool {
+      let totalsize = match self.parts.get_mut() {
+        Ok(mparts) => mparts.totalsize,
+        Err(poisoned) => poisoned.into_inner().totalsize,
+      };
+      pool.release(totalsize);
+    }
   }
 }
 
 #[cfg(test)]
 mod tests {
-  use super::MultiCache;
-  use std::sync::Arc;
+  use super::{Lookup, MultiCache, OversizePolicy, PutError, RemovalCause};
+  use super::clock::MockClock;
+  use super::policy::EvictionPolicy;
+  use std::collections::VecDeque;
+  use std::sync::{Arc, Mutex};
+  use std::time::Duration;
 
   #[test]
   fn evicts() {
@@ -215,13 +1069,13 @@ mod tests {
 
     cache.put(0, 0, 100);
 
-    assert_eq!(cache.contains_key(&0), true);
-    assert_eq!(cache.contains_key(&2), false);
+    assert!(cache.contains_key(&0));
+    assert!(!cache.contains_key(&2));
 
     cache.put(2, 2, 100);
 
-    assert_eq!(cache.contains_key(&0), false);
-    assert_eq!(cache.contains_key(&2), true);
+    assert!(!cache.contains_key(&0));
+    assert!(cache.contains_key(&2));
   }
 
   #[test]
@@ -245,4 +1099,316 @@ mod tests {
     assert_eq!(cache.remove(&0), None);
     assert_eq!(cache.get(&0), None);
   }
+
+  #[test]
+  fn formats_debug() {
+    let cache = MultiCache::<u32,u32>::new(10);
+    assert!(format!("{:?}", cache).starts_with("MultiCache { parts: "));
+    let sharded = super::ShardedMultiCache::<u32,u32>::new(10, 2);
+    assert!(format!("{:?}", sharded).starts_with("ShardedMultiCache { shards: [MultiCache "));
+  }
+
+  #[cfg(feature = "tracing")]
+  #[test]
+  fn formats_keys() {
+    let cache = MultiCache::<u32,u32>::new(200);
+    assert_eq!(cache.key_name(&7).len(), 16);
+    assert_eq!(cache.key_name(&7), cache.key_name(&7));
+
+    cache.set_key_formatter(|key| format!("{:?}", key));
+    assert_eq!(cache.key_name(&7), "7");
+  }
+
+  #[test]
+  fn keys_need_not_clone() {
+    #[derive(Hash, PartialEq, Eq)]
+    struct Key(u32);
+    let cache = MultiCache::new(200);
+
+    cache.put(Key(0), 0, 100);
+    cache.put(Key(1), 1, 100);
+    cache.get(&Key(0));
+    cache.put(Key(2), 2, 100);
+
+    assert_eq!(cache.get(&Key(0)), Some(Arc::new(0)));
+    assert_eq!(cache.get(&Key(1)), None);
+    assert_eq!(cache.remove(&Key(2)), Some(Arc::new(2)));
+  }
+
+  // Evicts in insertion order ignoring reads
+  struct Fifo(VecDeque<u32>);
+
+  impl EvictionPolicy<u32> for Fifo {
+    fn insert(&mut self, key: &u32, _bytes: usize) { self.0.push_back(*key); }
+    fn touch(&mut self, _key: &u32) {}
+    fn remove(&mut self, key: &u32) { self.0.retain(|k| k != key); }
+    fn evict(&mut self) -> Option<u32> { self.0.pop_front() }
+  }
+
+  #[test]
+  fn custom_policy() {
+    let cache = MultiCache::with_policy(200, Fifo(VecDeque::new()));
+
+    cache.put(0, 0, 100);
+    cache.put(1, 1, 100);
+    cache.get(&0);
+    cache.put(2, 2, 100);
+
+    assert_eq!(cache.get(&0), None);
+    assert_eq!(cache.get(&1), Some(Arc::new(1)));
+    assert_eq!(cache.get(&2), Some(Arc::new(2)));
+  }
+
+  #[test]
+  fn expires() {
+    let cache = MultiCache::new(200);
+
+    cache.put_with_ttl(0, 0, 100, Duration::from_secs(0));
+    cache.put_with_ttl(1, 1, 100, Duration::from_secs(3600));
+
+    assert!(!cache.contains_key(&0));
+    assert_eq!(cache.get(&0), None);
+    assert_eq!(cache.get(&1), Some(Arc::new(1)));
+
+    // The expired value no longer takes up space
+    cache.put(2, 2, 100);
+    assert_eq!(cache.get(&1), Some(Arc::new(1)));
+    assert_eq!(cache.get(&2), Some(Arc::new(2)));
+  }
+
+  #[test]
+  fn default_ttl() {
+    let cache = MultiCache::new(300);
+
+    cache.put(0, 0, 100);
+    cache.set_default_ttl(Some(Duration::from_secs(0)));
+    cache.put(1, 1, 100);
+    cache.put_with_ttl(2, 2, 100, Duration::from_secs(3600));
+
+    assert_eq!(cache.get(&0), Some(Arc::new(0)));
+    assert_eq!(cache.get(&1), None);
+    assert_eq!(cache.get(&2), Some(Arc::new(2)));
+  }
+
+  #[test]
+  fn purges_expired() {
+    let cache = MultiCache::new(200);
+
+    cache.put(0, 0, 100);
+    cache.put_with_ttl(1, 1, 100, Duration::from_secs(0));
+    cache.purge_expired();
+    cache.put(2, 2, 100);
+
+    assert_eq!(cache.get(&0), Some(Arc::new(0)));
+    assert_eq!(cache.get(&2), Some(Arc::new(2)));
+  }
+
+  #[test]
+  fn idle_expires() {
+    let cache = MultiCache::new(300);
+
+    cache.put(0, 0, 100);
+    cache.set_idle_timeout(Some(Duration::from_secs(3600)));
+    assert_eq!(cache.get(&0), Some(Arc::new(0)));
+
+    cache.set_idle_timeout(Some(Duration::from_secs(0)));
+    assert!(!cache.contains_key(&0));
+    assert_eq!(cache.get(&0), None);
+  }
+
+  #[test]
+  fn expires_with_clock() {
+    let cache = MultiCache::new(300);
+    let clock = Arc::new(MockClock::new());
+    cache.set_clock(clock.clone());
+
+    cache.put_with_ttl(0, 0, 100, Duration::from_secs(10));
+    cache.put(1, 1, 100);
+
+    clock.advance(Duration::from_secs(9));
+    assert_eq!(cache.get(&0), Some(Arc::new(0)));
+    clock.advance(Duration::from_secs(1));
+    assert_eq!(cache.get(&0), None);
+    assert_eq!(cache.get(&1), Some(Arc::new(1)));
+  }
+
+  #[test]
+  fn get_delays_idle() {
+    let cache = MultiCache::new(300);
+    let clock = Arc::new(MockClock::new());
+    cache.set_clock(clock.clone());
+    cache.set_idle_timeout(Some(Duration::from_secs(10)));
+
+    cache.put(0, 0, 100);
+    cache.put(1, 1, 100);
+
+    clock.advance(Duration::from_secs(6));
+    assert_eq!(cache.get(&0), Some(Arc::new(0)));
+    clock.advance(Duration::from_secs(6));
+    assert!(cache.contains_key(&0));
+    assert!(!cache.contains_key(&1));
+
+    clock.advance(Duration::from_secs(4));
+    cache.purge_expired();
+    assert!(!cache.contains_key(&0));
+  }
+
+  #[test]
+  fn serves_stale() {
+    let cache = MultiCache::new(300);
+    let clock = Arc::new(MockClock::new());
+    cache.set_clock(clock.clone());
+
+    cache.put_with_stale(0, 0, 100, Duration::from_secs(10), Duration::from_secs(50));
+    assert_eq!(cache.lookup(&0), Some(Lookup::Fresh(Arc::new(0))));
+    assert_eq!(cache.lookup(&1), None);
+
+    // Revalidating failed so nothing got put and the stale value keeps being used
+    clock.advance(Duration::from_secs(10));
+    assert_eq!(cache.lookup(&0), Some(Lookup::Stale(Arc::new(0))));
+    assert_eq!(cache.get(&0), Some(Arc::new(0)));
+    clock.advance(Duration::from_secs(49));
+    assert_eq!(cache.lookup(&0), Some(Lookup::Stale(Arc::new(0))));
+    clock.advance(Duration::from_secs(1));
+    assert_eq!(cache.lookup(&0), None);
+
+    // Revalidating makes it fresh again
+    cache.put_with_stale(0, 0, 100, Duration::from_secs(10), Duration::from_secs(50));
+    clock.advance(Duration::from_secs(10));
+    cache.put_with_stale(0, 1, 100, Duration::from_secs(10), Duration::from_secs(50));
+    assert_eq!(cache.lookup(&0), Some(Lookup::Fresh(Arc::new(1))));
+  }
+
+  #[test]
+  fn caches_absence() {
+    let cache = MultiCache::new(300);
+    let clock = Arc::new(MockClock::new());
+    cache.set_clock(clock.clone());
+    let removals = Arc::new(Mutex::new(Vec::new()));
+    let log = removals.clone();
+    cache.set_eviction_listener(move |key, val: Arc<u32>, _, _| {
+      log.lock().unwrap().push((key, *val));
+    });
+
+    cache.put(0, 0, 100);
+    cache.put_absent(1, 1, Duration::from_secs(10));
+
+    assert_eq!(cache.lookup(&0), Some(Lookup::Fresh(Arc::new(0))));
+    assert_eq!(cache.lookup(&1), Some(Lookup::Absent));
+    assert_eq!(cache.lookup(&2), None);
+    assert_eq!(cache.get(&1), None);
+    assert!(!cache.contains_key(&1));
+
+    clock.advance(Duration::from_secs(10));
+    assert_eq!(cache.lookup(&1), None);
+    assert!(removals.lock().unwrap().is_empty());
+  }
+
+  #[test]
+  fn oversize_policies() {
+    let cache = MultiCache::new(200);
+    let removals = Arc::new(Mutex::new(Vec::new()));
+    let log = removals.clone();
+    cache.set_eviction_listener(move |key, val: Arc<u32>, _, cause| {
+      log.lock().unwrap().push((key, *val, cause));
+    });
+    cache.put(0, 0, 100);
+    cache.put(1, 1, 100);
+
+    cache.set_oversize_policy(OversizePolicy::Reject);
+    assert_eq!(cache.try_put(2, 2, 300), Err(PutError::TooLarge { bytes: 300, maxsize: 200 }));
+    assert_eq!(cache.try_put(1, 3, 300), Err(PutError::TooLarge { bytes: 300, maxsize: 200 }));
+    assert_eq!(cache.get(&0), Some(Arc::new(0)));
+    assert_eq!(cache.get(&1), None);
+    assert_eq!(*removals.lock().unwrap(), vec![(1, 1, RemovalCause::Explicit)]);
+
+    cache.put(1, 1, 100);
+    cache.set_oversize_policy(OversizePolicy::Bypass);
+    assert_eq!(cache.try_put(1, 3, 300), Ok(Arc::new(3)));
+    assert_eq!(cache.get(&1), None);
+    assert_eq!(cache.try_put(2, 2, 300), Ok(Arc::new(2)));
+    assert_eq!(cache.get(&0), Some(Arc::new(0)));
+    assert_eq!(cache.get(&2), None);
+
+    cache.set_oversize_policy(OversizePolicy::AdmitAlone);
+    assert_eq!(cache.try_put(2, 2, 300), Ok(Arc::new(2)));
+    assert_eq!(cache.get(&0), None);
+    assert_eq!(cache.get(&2), Some(Arc::new(2)));
+    assert_eq!(*removals.lock().unwrap(), vec![
+      (1, 1, RemovalCause::Explicit),
+      (1, 1, RemovalCause::Explicit),
+      (0, 0, RemovalCause::Evicted),
+    ]);
+  }
+
+  #[test]
+  fn changes_max_bytes() {
+    let cache = MultiCache::new(300);
+    let removals = Arc::new(Mutex::new(Vec::new()));
+    let log = removals.clone();
+    cache.set_eviction_listener(move |key, _: Arc<u32>, _, cause| {
+      log.lock().unwrap().push((key, cause));
+    });
+
+    cache.put(0, 0, 100);
+    cache.put(1, 1, 100);
+    cache.put(2, 2, 100);
+    cache.set_max_bytes(100);
+    assert_eq!(cache.totalsize(), 100);
+    assert_eq!(cache.get(&2), Some(Arc::new(2)));
+    assert_eq!(*removals.lock().unwrap(), vec![
+      (0, RemovalCause::Evicted),
+      (1, RemovalCause::Evicted),
+    ]);
+
+    cache.set_max_bytes(200);
+    cache.put(3, 3, 100);
+    assert_eq!(cache.maxsize(), 200);
+    assert_eq!(cache.get(&2), Some(Arc::new(2)));
+    assert_eq!(cache.get(&3), Some(Arc::new(3)));
+  }
+
+  #[test]
+  fn listens_to_removals() {
+    let cache = Arc::new(MultiCache::new(200));
+    let removals = Arc::new(Mutex::new(Vec::new()));
+    let log = removals.clone();
+    cache.set_eviction_listener(move |key, val: Arc<u32>, bytes, cause| {
+      log.lock().unwrap().push((key, *val, bytes, cause));
+    });
+
+    cache.put_with_ttl(0, 0, 50, Duration::from_secs(0));
+    cache.put(1, 1, 100);
+    cache.put(1, 2, 100);
+    cache.put(2, 3, 100);
+    cache.put(3, 4, 100);
+    cache.get(&0);
+    cache.remove(&2);
+    cache.clear();
+
+    assert_eq!(*removals.lock().unwrap(), vec![
+      (1, 1, 100, RemovalCause::Replaced),
+      (0, 0, 50, RemovalCause::Expired),
+      (1, 2, 100, RemovalCause::Evicted),
+      (2, 3, 100, RemovalCause::Explicit),
+      (3, 4, 100, RemovalCause::Cleared),
+    ]);
+  }
+
+  #[test]
+  fn listener_reenters() {
+    let cache = Arc::new(MultiCache::new(100));
+    let inner = Arc::downgrade(&cache);
+    cache.set_eviction_listener(move |key, val, _, cause| {
+      if cause == RemovalCause::Evicted {
+        inner.upgrade().unwrap().put(key + 100, *val, 0);
+      }
+    });
+
+    cache.put(0, 0, 100);
+    cache.put(1, 1, 100);
+
+    assert_eq!(cache.get(&100), Some(Arc::new(0)));
+    assert_eq!(cache.get(&1), Some(Arc::new(1)));
+  }
 }
@@ -0,0 +1,209 @@
+use std::collections::HashSet;
+use std::convert::Infallible;
+use std::fmt;
+use std::hash::Hash;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use crate::{Loaded, MultiCache};
+use crate::policy::{EvictionPolicy, Lru};
+
+type Loader<K,V> = Arc<dyn Fn(&K) -> Loaded<V> + Send + Sync>;
+
+/// A cache that knows how to load its own elements. Missing keys are loaded the first
+/// time they are asked for, with only one thread running the loader for a given key.
+///
+/// Elements that were put in longer ago than the refresh interval get reloaded the next
+/// time they're read while the read itself still returns the current value right away,
+/// so frequently used elements never have to wait on a load once they're in the cache.
+/// Reloads are done one at a time by a single background thread that stops when the
+/// `LoadingCache` is dropped.
+pub struct LoadingCache<K,V,P=Lru<K>> {
+  cache: Arc<MultiCache<K,V,P>>,
+  loader: Loader<K,V>,
+  refresh_after: Duration,
+  refreshing: Arc<Mutex<HashSet<K>>>,
+  refresher: mpsc::Sender<(K, Arc<V>)>,
+}
+
+impl<K,V,P> fmt::Debug for LoadingCache<K,V,P> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{{ {:?} refresh_after, {} refreshing }}",
+      self.refresh_after, self.refreshing.lock().unwrap().len())
+  }
+}
+
+// Reload the keys sent over until the sender is dropped, replacing the value that was
+// current when the refresh was asked for. A panicking loader only loses that one
+// refresh.
+fn refresher<K,V,P>(cache: Arc<MultiCache<K,V,P>>, loader: Loader<K,V>, refreshing: Arc<Mutex<HashSet<K>>>) -> mpsc::Sender<(K, Arc<V>)>
+where K: Hash+Eq+Clone+Send+Sync+'static, V: Send+Sync+'static, P: EvictionPolicy<K>+Send+Sync+'static {
+  let (sender, receiver) = mpsc::channel::<(K, Arc<V>)>();
+  thread::Builder::new().name("multicache-refresh".to_string()).spawn(move || {
+    for (key, current) in receiver {
+      #[cfg(feature = "tracing")]
+      let _span = tracing::debug_span!("refresh", key = %cache.key_name(&key)).entered();
+      if let Ok(loaded) = panic::catch_unwind(AssertUnwindSafe(|| loader(&key))) {
+        cache.replace(key.clone(), &current, loaded);
+      }
+      refreshing.lock().unwrap().remove(&key);
+    }
+  }).unwrap();
+  sender
+}
+
+impl<K,V,P> LoadingCache<K,V,P>
+where K: Hash+Eq+Clone+Send+Sync+'static, V: Send+Sync+'static, P: EvictionPolicy<K>+Send+Sync+'static {
+  /// Wrap a cache so that elements missing from it are loaded by the loader, which
+  /// returns the value along with its bytesize or `Loaded::Absent` for keys that don't
+  /// exist, and ones older than refresh_after get reloaded in the background
+  pub fn new<F,L>(cache: Arc<MultiCache<K,V,P>>, refresh_after: Duration, loader: F) -> LoadingCache<K,V,P>
+  where F: Fn(&K) -> L + Send + Sync + 'static, L: Into<Loaded<V>> {
+    let loader: Loader<K,V> = Arc::new(move |key: &K| loader(key).into());
+    let refreshing = Arc::new(Mutex::new(HashSet::new()));
+    LoadingCache {
+      refresher: refresher(cache.clone(), loader.clone(), refreshing.clone()),
+      cache,
+      loader,
+      refresh_after,
+      refreshing,
+    }
+  }
+
+  /// The cache the elements are kept in
+  pub fn cache(&self) -> &Arc<MultiCache<K,V,P>> {
+    &self.cache
+  }
+
+  /// Get an element, loading it if it's not in the cache and starting a reload in the
+  /// background if it's due for a refresh. None means the key is known to be absent.
+  pub fn get(&self, key: &K) -> Option<Arc<V>> {
+    match self.cache.get_aged(key, true) {
+      Some((val, age)) => {
+        if age >= self.refresh_after {
+          self.schedule(key, &val);
+        }
+        Some(val)
+      },
+      // The miss was already counted
+      None => match self.cache.load(key.clone(), false, || Ok::<_, Infallible>((self.loader)(key))) {
+        Ok(val) => val,
+        Err(never) => match never {},
+      },
+    }
+  }
+
+  /// Reload an element in the background, unless that's already being done, and swap
+  /// it into the cache once it's loaded. Nothing is done for keys that aren't in the
+  /// cache and the reloaded value is dropped if the element was removed or replaced
+  /// while it was being loaded.
+  pub fn refresh(&self, key: &K) {
+    if let Some((val, _)) = self.cache.get_aged(key, false) {
+      self.schedule(key, &val);
+    }
+  }
+
+  fn schedule(&self, key: &K, current: &Arc<V>) {
+    if self.refreshing.lock().unwrap().insert(key.clone()) {
+      let _ = self.refresher.send((key.clone(), current.clone()));
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::{Loaded, MultiCache};
+  use crate::clock::MockClock;
+  use super::LoadingCache;
+  use std::sync::{Arc, Mutex};
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use std::thread;
+  use std::time::Duration;
+
+  #[test]
+  fn loads_missing() {
+    let inner = Arc::new(MultiCache::new(1000));
+    let cache = LoadingCache::new(inner, Duration::from_secs(60), |key| (*key * 10, 100));
+
+    assert_eq!(cache.get(&1), Some(Arc::new(10)));
+    assert_eq!(cache.cache().get(&1), Some(Arc::new(10)));
+  }
+
+  #[test]
+  fn remembers_absent() {
+    let inner = Arc::new(MultiCache::new(1000));
+    let loads = Arc::new(AtomicUsize::new(0));
+    let counter = loads.clone();
+    let cache = LoadingCache::new(inner, Duration::from_secs(60), move |key| {
+      counter.fetch_add(1, Ordering::SeqCst);
+      match *key {
+        0 => Loaded::Absent(10, Duration::from_secs(60)),
+        key => Loaded::Value(key, 100),
+      }
+    });
+
+    assert_eq!(cache.get(&0), None);
+    assert_eq!(cache.get(&0), None);
+    assert_eq!(cache.get(&1), Some(Arc::new(1)));
+    assert_eq!(loads.load(Ordering::SeqCst), 2);
+  }
+
+  #[test]
+  fn refreshes_in_background() {
+    let inner = Arc::new(MultiCache::new(1000));
+    let clock = Arc::new(MockClock::new());
+    inner.set_clock(clock.clone());
+    let loads = Arc::new(AtomicUsize::new(0));
+    let counter = loads.clone();
+    let cache = LoadingCache::new(inner, Duration::from_secs(60), move |_| {
+      (counter.fetch_add(1, Ordering::SeqCst), 100)
+    });
+
+    assert_eq!(cache.get(&0), Some(Arc::new(0)));
+    clock.advance(Duration::from_secs(30));
+    assert_eq!(cache.get(&0), Some(Arc::new(0)));
+    assert_eq!(loads.load(Ordering::SeqCst), 1);
+
+    // Past the refresh interval the old value still comes back right away
+    clock.advance(Duration::from_secs(30));
+    assert_eq!(cache.get(&0), Some(Arc::new(0)));
+    for _ in 0..100 {
+      if cache.cache().get(&0) == Some(Arc::new(1)) {
+        break
+      }
+      thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(cache.cache().get(&0), Some(Arc::new(1)));
+    assert_eq!(cache.get(&0), Some(Arc::new(1)));
+  }
+
+  #[test]
+  fn refresh_skips_removed() {
+    let inner = Arc::new(MultiCache::new(1000));
+    let clock = Arc::new(MockClock::new());
+    inner.set_clock(clock.clone());
+    let gate = Arc::new(Mutex::new(()));
+    let loader_gate = gate.clone();
+    let cache = LoadingCache::new(inner, Duration::from_secs(60), move |key| {
+      let _gate = loader_gate.lock().unwrap();
+      (*key, 100)
+    });
+
+    assert_eq!(cache.get(&0), Some(Arc::new(0)));
+    clock.advance(Duration::from_secs(60));
+    let held = gate.lock().unwrap();
+    assert_eq!(cache.get(&0), Some(Arc::new(0)));
+    cache.cache().remove(&0);
+    drop(held);
+
+    for _ in 0..100 {
+      if cache.refreshing.lock().unwrap().is_empty() {
+        break
+      }
+      thread::sleep(Duration::from_millis(10));
+    }
+    assert!(cache.refreshing.lock().unwrap().is_empty());
+    assert_eq!(cache.cache().get(&0), None);
+  }
+}
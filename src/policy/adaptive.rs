@@ -0,0 +1,235 @@
+use linked_hash_map::LinkedHashMap;
+use std::hash::Hash;
+use super::{resize, EvictionPolicy};
+
+/// Adaptive Replacement Cache (ARC) eviction with sizes accounted in bytes. Entries
+/// that were only used once are kept in a recency list and ones that were used again
+/// in a frequency list. The keys of evicted entries are remembered in ghost lists and
+/// finding one of them again when inserting shifts the byte target between the two
+/// lists towards the one that would have kept it. The split tunes itself to the
+/// workload without any configuration.
+pub struct Adaptive<K> {
+  t1: LinkedHashMap<K,usize>,
+  t2: LinkedHashMap<K,usize>,
+  b1: LinkedHashMap<K,usize>,
+  b2: LinkedHashMap<K,usize>,
+  t1_bytes: usize,
+  t2_bytes: usize,
+  b1_bytes: usize,
+  b2_bytes: usize,
+  // Target number of bytes for t1
+  p: usize,
+  capacity: usize,
+}
+
+impl<K: Hash+Eq> Adaptive<K> {
+  pub fn new() -> Adaptive<K> {
+    Adaptive {
+      t1: LinkedHashMap::new(),
+      t2: LinkedHashMap::new(),
+      b1: LinkedHashMap::new(),
+      b2: LinkedHashMap::new(),
+      t1_bytes: 0,
+      t2_bytes: 0,
+      b1_bytes: 0,
+      b2_bytes: 0,
+      p: 0,
+      capacity: 0,
+    }
+  }
+
+  // Whether the next eviction comes out of the recency list
+  fn evict_recent(&self) -> bool {
+    !self.t1.is_empty() && (self.t1_bytes > self.p || self.t2.is_empty())
+  }
+
+  // Ghosts only need to remember as much as would have fit in the cache
+  fn trim_ghosts(&mut self) {
+    while self.t1_bytes + self.b1_bytes > self.capacity {
+      match self.b1.pop_front() {
+        None => break,
+        Some((_, bytes)) => self.b1_bytes -= bytes,
+      }
+    }
+    while self.t1_bytes + self.t2_bytes + self.b1_bytes + self.b2_bytes > 2 * self.capacity {
+      match self.b2.pop_front() {
+        None => break,
+        Some((_, bytes)) => self.b2_bytes -= bytes,
+      }
+    }
+  }
+}
+
+impl<K: Hash+Eq> Default for Adaptive<K> {
+  fn default() -> Adaptive<K> {
+    Adaptive::new()
+  }
+}
+
+impl<K: Hash+Eq+Clone> EvictionPolicy<K> for Adaptive<K> {
+  fn set_capacity(&mut self, maxsize: usize) {
+    self.capacity = maxsize;
+    self.p = self.p.min(maxsize);
+    self.trim_ghosts();
+  }
+
+  fn insert(&mut self, key: &K, bytes: usize) {
+    if let Some(ghost) = self.b1.remove(key) {
+      // Would have been a hit with a larger recency list
+      let ratio = if self.b1_bytes >= self.b2_bytes { 1 } else { self.b2_bytes / self.b1_bytes.max(1) };
+      self.b1_bytes -= ghost;
+      self.p = self.p.saturating_add(ratio.saturating_mul(bytes)).min(self.capacity);
+      self.t2_bytes += bytes;
+      self.t2.insert(key.clone(), bytes);
+    } else if let Some(ghost) = self.b2.remove(key) {
+      // Would have been a hit with a larger frequency list
+      let ratio = if self.b2_bytes >= self.b1_bytes { 1 } else { self.b1_bytes / self.b2_bytes.max(1) };
+      self.b2_bytes -= ghost;
+      self.p = self.p.saturating_sub(ratio.saturating_mul(bytes));
+      self.t2_bytes += bytes;
+      self.t2.insert(key.clone(), bytes);
+    } else {
+      self.t1_bytes += bytes;
+      self.t1.insert(key.clone(), bytes);
+    }
+    self.trim_ghosts();
+  }
+
+  fn touch(&mut self, key: &K) {
+    if let Some(bytes) = self.t1.remove(key) {
+      self.t1_bytes -= bytes;
+      self.t2_bytes += bytes;
+      self.t2.insert(key.clone(), bytes);
+    } else {
+      self.t2.get_refresh(key);
+    }
+  }
+
+  // Stays in whichever list it was in
+  fn update(&mut self, key: &K, bytes: usize) {
+    if resize(&mut self.t1, &mut self.t1_bytes, key, bytes) || resize(&mut self.t2, &mut self.t2_bytes, key, bytes) {
+      self.trim_ghosts();
+    } else {
+      self.insert(key, bytes);
+    }
+  }
+
+  fn remove(&mut self, key: &K) {
+    if let Some(bytes) = self.t1.remove(key) {
+      self.t1_bytes -= bytes;
+    } else if let Some(bytes) = self.t2.remove(key) {
+      self.t2_bytes -= bytes;
+    }
+  }
+
+  fn evict(&mut self) -> Option<K> {
+    let evicted = if self.evict_recent() {
+      self.t1.pop_front().map(|(key, bytes)| {
+        self.t1_bytes -= bytes;
+        self.b1_bytes += bytes;
+        self.b1.insert(key.clone(), bytes);
+        key
+      })
+    } else {
+      self.t2.pop_front().map(|(key, bytes)| {
+        self.t2_bytes -= bytes;
+        self.b2_bytes += bytes;
+        self.b2.insert(key.clone(), bytes);
+        key
+      })
+    };
+
+    self.trim_ghosts();
+    evicted
+  }
+
+  fn peek(&self) -> Option<&K> {
+    let list = if self.evict_recent() { &self.t1 } else { &self.t2 };
+    list.front().map(|(key, _)| key)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::MultiCache;
+  use crate::policy::EvictionPolicy;
+  use super::Adaptive;
+  use std::sync::Arc;
+
+  #[test]
+  fn keeps_frequent() {
+    let cache = MultiCache::with_policy(1000, Adaptive::new());
+
+    for i in 0..5 {
+      cache.put(i, i, 100);
+      cache.get(&i);
+    }
+    for i in 100..200 {
+      cache.put(i, i, 100);
+    }
+
+    for i in 0..5 {
+      assert_eq!(cache.get(&i), Some(Arc::new(i)));
+    }
+  }
+
+  #[test]
+  fn ghost_hits_adapt() {
+    let mut arc = Adaptive::new();
+    arc.set_capacity(300);
+
+    arc.insert(&0, 100);
+    arc.touch(&0);
+    arc.insert(&1, 100);
+    arc.insert(&2, 100);
+    arc.insert(&3, 100);
+    assert_eq!(arc.evict(), Some(1));
+    assert_eq!(arc.p, 0);
+
+    // Coming back from the recency ghost list grows its target by its size
+    arc.insert(&1, 100);
+    assert_eq!(arc.p, 100);
+    assert_eq!(arc.t2_bytes, 200);
+    assert_eq!(arc.evict(), Some(2));
+    assert_eq!(arc.evict(), Some(0));
+
+    // And coming back from the frequency ghost list shrinks it again
+    arc.insert(&0, 50);
+    assert_eq!(arc.p, 50);
+  }
+
+  #[test]
+  fn update_keeps_frequent() {
+    let mut arc = Adaptive::new();
+    arc.set_capacity(300);
+
+    arc.insert(&0, 100);
+    arc.touch(&0);
+    arc.update(&0, 50);
+    assert_eq!(arc.t2_bytes, 50);
+    assert!(arc.t1.is_empty());
+  }
+
+  #[test]
+  fn zero_byte_ghosts() {
+    let mut arc = Adaptive::new();
+    arc.set_capacity(300);
+
+    arc.insert(&1, 100);
+    arc.touch(&1);
+    arc.insert(&0, 0);
+    assert_eq!(arc.evict(), Some(1));
+    assert_eq!(arc.evict(), Some(0));
+
+    // The recency ghost list only holds zero bytes
+    arc.insert(&0, 0);
+    assert_eq!(arc.p, 0);
+
+    let cache = MultiCache::with_policy(200, Adaptive::new());
+    for i in 0..1000u32 {
+      cache.put(i % 7, i, if i % 3 == 0 { 0 } else { 100 });
+      cache.get(&(i % 5));
+    }
+    assert!(cache.totalsize() <= 200);
+  }
+}
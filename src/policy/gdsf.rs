@@ -0,0 +1,202 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use super::EvictionPolicy;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Priority(f64);
+
+impl Eq for Priority {}
+
+impl PartialOrd for Priority {
+  fn partial_cmp(&self, other: &Priority) -> Option<Ordering> {
+    Some(self.cmp(other))
+  }
+}
+
+impl Ord for Priority {
+  fn cmp(&self, other: &Priority) -> Ordering {
+    self.0.total_cmp(&other.0)
+  }
+}
+
+struct GdsfEntry {
+  frequency: u64,
+  bytes: usize,
+  priority: Priority,
+  seq: u64,
+}
+
+/// GreedyDual-Size-Frequency eviction. Every entry gets a priority of its access
+/// frequency times the cost of fetching it again divided by its size and the entry
+/// with the lowest priority gets evicted first. Every eviction raises the base that
+/// new priorities are computed from to the priority of the evicted entry so entries
+/// that stop being used eventually age out no matter how small they are.
+///
+/// By default every entry costs the same to fetch again which makes the cache favor
+/// keeping many small frequently used entries over a few large ones.
+pub struct Gdsf<K> {
+  entries: HashMap<K,GdsfEntry>,
+  queue: BTreeMap<(Priority,u64),K>,
+  inflation: f64,
+  seq: u64,
+  cost: Box<dyn Fn(usize) -> f64 + Send + Sync>,
+}
+
+impl<K: Hash+Eq> Gdsf<K> {
+  pub fn new() -> Gdsf<K> {
+    Self::with_cost(|_| 1.0)
+  }
+
+  /// Create a new policy where the cost of fetching an entry again is calculated from
+  /// its size in bytes
+  pub fn with_cost<F>(cost: F) -> Gdsf<K>
+  where F: Fn(usize) -> f64 + Send + Sync + 'static {
+    Gdsf {
+      entries: HashMap::new(),
+      queue: BTreeMap::new(),
+      inflation: 0.0,
+      seq: 0,
+      cost: Box::new(cost),
+    }
+  }
+
+  fn priority(&self, frequency: u64, bytes: usize) -> Priority {
+    let cost = (self.cost)(bytes);
+    Priority(self.inflation + frequency as f64 * cost / bytes.max(1) as f64)
+  }
+
+  // Give an entry a new frequency and size, moving it in the queue
+  fn requeue(&mut self, key: &K, frequency: u64, bytes: usize) {
+    let priority = self.priority(frequency, bytes);
+    self.seq += 1;
+
+    let entry = self.entries.get_mut(key).unwrap();
+    let queued = self.queue.remove(&(entry.priority, entry.seq)).unwrap();
+    entry.frequency = frequency;
+    entry.bytes = bytes;
+    entry.priority = priority;
+    entry.seq = self.seq;
+    self.queue.insert((priority, self.seq), queued);
+  }
+}
+
+impl<K: Hash+Eq> Default for Gdsf<K> {
+  fn default() -> Gdsf<K> {
+    Gdsf::new()
+  }
+}
+
+impl<K: Hash+Eq+Clone> EvictionPolicy<K> for Gdsf<K> {
+  fn insert(&mut self, key: &K, bytes: usize) {
+    let priority = self.priority(1, bytes);
+    self.seq += 1;
+    self.queue.insert((priority, self.seq), key.clone());
+    self.entries.insert(key.clone(), GdsfEntry {
+      frequency: 1,
+      bytes,
+      priority,
+      seq: self.seq,
+    });
+  }
+
+  fn touch(&mut self, key: &K) {
+    if let Some((frequency, bytes)) = self.entries.get(key).map(|entry| (entry.frequency, entry.bytes)) {
+      self.requeue(key, frequency + 1, bytes);
+    }
+  }
+
+  // Keeps the frequency it had
+  fn update(&mut self, key: &K, bytes: usize) {
+    match self.entries.get(key).map(|entry| entry.frequency) {
+      None => self.insert(key, bytes),
+      Some(frequency) => self.requeue(key, frequency, bytes),
+    }
+  }
+
+  fn remove(&mut self, key: &K) {
+    if let Some(entry) = self.entries.remove(key) {
+      self.queue.remove(&(entry.priority, entry.seq));
+    }
+  }
+
+  fn evict(&mut self) -> Option<K> {
+    let ((priority, _), key) = self.queue.pop_first()?;
+    self.inflation = priority.0;
+    self.entries.remove(&key);
+    Some(key)
+  }
+
+  fn peek(&self) -> Option<&K> {
+    self.queue.first_key_value().map(|(_, key)| key)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::MultiCache;
+  use crate::policy::EvictionPolicy;
+  use super::Gdsf;
+  use std::sync::Arc;
+
+  #[test]
+  fn evicts_large_cold() {
+    let cache = MultiCache::with_policy(1000, Gdsf::new());
+
+    for i in 0..10 {
+      cache.put(i, i, 10);
+      cache.get(&i);
+    }
+    cache.put(100, 100, 900);
+    cache.put(10, 10, 10);
+
+    assert_eq!(cache.get(&100), None);
+    for i in 0..11 {
+      assert_eq!(cache.get(&i), Some(Arc::new(i)));
+    }
+  }
+
+  #[test]
+  fn update_keeps_frequency() {
+    let mut gdsf = Gdsf::new();
+
+    gdsf.insert(&0, 100);
+    gdsf.touch(&0);
+    gdsf.touch(&0);
+    gdsf.update(&0, 50);
+    assert_eq!(gdsf.entries[&0].frequency, 3);
+    assert_eq!(gdsf.entries[&0].bytes, 50);
+
+    gdsf.insert(&1, 50);
+    assert_eq!(gdsf.evict(), Some(1));
+
+    // Putting a value in again goes through update
+    let cache = MultiCache::with_policy(200, Gdsf::new());
+    cache.put(0, 0, 100);
+    for _ in 0..3 {
+      cache.get(&0);
+    }
+    cache.put(0, 1, 100);
+    cache.put(1, 1, 100);
+    cache.put(2, 2, 100);
+    assert_eq!(cache.get(&0), Some(Arc::new(1)));
+    assert_eq!(cache.get(&1), None);
+  }
+
+  #[test]
+  fn ages_out() {
+    let cache = MultiCache::with_policy(300, Gdsf::new());
+
+    cache.put(0, 0, 100);
+    for _ in 0..3 {
+      cache.get(&0);
+    }
+    // Each eviction inflates the priority of newer entries until they win
+    for i in 1..10 {
+      cache.put(i, i, 100);
+      cache.get(&i);
+    }
+
+    assert_eq!(cache.get(&0), None);
+  }
+}
@@ -0,0 +1,37 @@
+use std::marker::PhantomData;
+use super::EvictionPolicy;
+
+/// Least recently used eviction, the default policy. Entries are evicted in the order
+/// they were last inserted or read regardless of their size. The cache keeps that order
+/// in its own map so this policy doesn't hold any state or a copy of the keys.
+pub struct Lru<K> {
+  keys: PhantomData<K>,
+}
+
+impl<K> Lru<K> {
+  pub fn new() -> Lru<K> {
+    Lru {
+      keys: PhantomData,
+    }
+  }
+}
+
+impl<K> Default for Lru<K> {
+  fn default() -> Lru<K> {
+    Lru::new()
+  }
+}
+
+impl<K> EvictionPolicy<K> for Lru<K> {
+  const CACHE_ORDER: bool = true;
+
+  fn insert(&mut self, _key: &K, _bytes: usize) {}
+
+  fn touch(&mut self, _key: &K) {}
+
+  fn remove(&mut self, _key: &K) {}
+
+  fn evict(&mut self) -> Option<K> {
+    None
+  }
+}
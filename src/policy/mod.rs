@@ -0,0 +1,83 @@
+//! Eviction policies that decide which entries get dropped when the cache goes over
+//! its byte budget.
+//!
+//! The cache itself only stores the values and keeps track of their sizes. Every time
+//! an entry is added, read or removed the policy gets told about it and when the cache
+//! needs to reclaim space it asks the policy for the next key to evict.
+
+mod adaptive;
+mod gdsf;
+mod lru;
+mod s3fifo;
+mod sieve;
+mod tinylfu;
+
+pub use self::adaptive::Adaptive;
+pub use self::gdsf::Gdsf;
+pub use self::lru::Lru;
+pub use self::s3fifo::S3Fifo;
+pub use self::sieve::Sieve;
+pub use self::tinylfu::TinyLfu;
+
+use linked_hash_map::LinkedHashMap;
+use std::hash::Hash;
+
+/// A strategy for picking which entries to evict from a `MultiCache`
+pub trait EvictionPolicy<K> {
+  /// Whether hits can be recorded through `touch_shared`, which lets the cache serve
+  /// reads under a shared lock instead of an exclusive one
+  const SHARED_HITS: bool = false;
+
+  /// Whether the policy evicts in the order the cache keeps its elements in, least
+  /// recently inserted or read first. The cache then does the evicting on its own and
+  /// none of the other methods get called
+  const CACHE_ORDER: bool = false;
+
+  /// Called with the byte budget of the cache whenever it is set
+  fn set_capacity(&mut self, _maxsize: usize) {}
+
+  /// A new key has been added to the cache taking up a given number of bytes
+  fn insert(&mut self, key: &K, bytes: usize);
+
+  /// A key that is in the cache has been read
+  fn touch(&mut self, key: &K);
+
+  /// A key that is in the cache has been read while only holding a shared lock. Only
+  /// gets called for policies that set `SHARED_HITS`
+  fn touch_shared(&self, _key: &K) {}
+
+  /// A key that is in the cache has been put in again, now taking up a given number of
+  /// bytes. Policies that keep track of how keys get used can hold on to that instead
+  /// of starting the key over, by default it's removed and inserted again
+  fn update(&mut self, key: &K, bytes: usize) {
+    self.remove(key);
+    self.insert(key, bytes);
+  }
+
+  /// A key has been removed from the cache for any reason other than being returned
+  /// by `evict`
+  fn remove(&mut self, key: &K);
+
+  /// Pick the next key to evict from the cache and forget about it. Returning None
+  /// means there is nothing left to evict
+  fn evict(&mut self) -> Option<K>;
+
+  /// The key `evict` would most likely pick next, without evicting it. This is used to
+  /// compare caches that share a memory pool, None means the policy can't tell
+  fn peek(&self) -> Option<&K> {
+    None
+  }
+}
+
+// Change the bytes of a key in one of the lists of a policy, moving it to the back,
+// returns whether the key was there
+fn resize<K: Hash+Eq>(list: &mut LinkedHashMap<K,usize>, total: &mut usize, key: &K, bytes: usize) -> bool {
+  match list.get_refresh(key) {
+    None => false,
+    Some(old) => {
+      *total = *total - *old + bytes;
+      *old = bytes;
+      true
+    },
+  }
+}
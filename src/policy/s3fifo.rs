@@ -0,0 +1,201 @@
+use linked_hash_map::LinkedHashMap;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU8, Ordering};
+use super::EvictionPolicy;
+
+const MAX_FREQUENCY: u8 = 3;
+
+struct S3Entry {
+  bytes: usize,
+  frequency: AtomicU8,
+}
+
+impl S3Entry {
+  fn new(bytes: usize) -> S3Entry {
+    S3Entry {
+      bytes,
+      frequency: AtomicU8::new(0),
+    }
+  }
+}
+
+/// S3-FIFO eviction. New entries go into a small FIFO queue taking 10% of the cache
+/// and only move on to the main FIFO queue if they get used again before reaching
+/// its end, so entries that are used once get evicted quickly. Entries at the end of
+/// the main queue get reinserted while they have been used since they were last
+/// looked at. The keys evicted from the small queue are remembered in a ghost queue
+/// so that they go straight to the main queue if they get inserted again.
+///
+/// Hits only bump a small counter with atomics so `get` only needs a shared lock on
+/// the cache.
+pub struct S3Fifo<K> {
+  small: LinkedHashMap<K,S3Entry>,
+  main: LinkedHashMap<K,S3Entry>,
+  ghost: LinkedHashMap<K,usize>,
+  small_bytes: usize,
+  main_bytes: usize,
+  ghost_bytes: usize,
+  capacity: usize,
+}
+
+impl<K: Hash+Eq> S3Fifo<K> {
+  pub fn new() -> S3Fifo<K> {
+    S3Fifo {
+      small: LinkedHashMap::new(),
+      main: LinkedHashMap::new(),
+      ghost: LinkedHashMap::new(),
+      small_bytes: 0,
+      main_bytes: 0,
+      ghost_bytes: 0,
+      capacity: 0,
+    }
+  }
+
+  fn evict_small(&self) -> bool {
+    self.small_bytes > self.capacity / 10 || self.main.is_empty()
+  }
+
+  fn remember(&mut self, key: K, bytes: usize) {
+    self.ghost_bytes += bytes;
+    self.ghost.insert(key, bytes);
+    while self.ghost_bytes > self.capacity - self.capacity / 10 {
+      match self.ghost.pop_front() {
+        None => break,
+        Some((_, bytes)) => self.ghost_bytes -= bytes,
+      }
+    }
+  }
+}
+
+impl<K: Hash+Eq> Default for S3Fifo<K> {
+  fn default() -> S3Fifo<K> {
+    S3Fifo::new()
+  }
+}
+
+impl<K: Hash+Eq+Clone> EvictionPolicy<K> for S3Fifo<K> {
+  const SHARED_HITS: bool = true;
+
+  fn set_capacity(&mut self, maxsize: usize) {
+    self.capacity = maxsize;
+  }
+
+  fn insert(&mut self, key: &K, bytes: usize) {
+    if let Some(ghost) = self.ghost.remove(key) {
+      self.ghost_bytes -= ghost;
+      self.main_bytes += bytes;
+      self.main.insert(key.clone(), S3Entry::new(bytes));
+    } else {
+      self.small_bytes += bytes;
+      self.small.insert(key.clone(), S3Entry::new(bytes));
+    }
+  }
+
+  fn touch(&mut self, key: &K) {
+    self.touch_shared(key);
+  }
+
+  fn touch_shared(&self, key: &K) {
+    if let Some(entry) = self.small.get(key).or_else(|| self.main.get(key)) {
+      let _ = entry.frequency.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |freq| {
+        if freq < MAX_FREQUENCY { Some(freq + 1) } else { None }
+      });
+    }
+  }
+
+  // Keeps its place in its queue and its frequency
+  fn update(&mut self, key: &K, bytes: usize) {
+    if let Some(entry) = self.small.get_mut(key) {
+      self.small_bytes = self.small_bytes - entry.bytes + bytes;
+      entry.bytes = bytes;
+    } else if let Some(entry) = self.main.get_mut(key) {
+      self.main_bytes = self.main_bytes - entry.bytes + bytes;
+      entry.bytes = bytes;
+    } else {
+      self.insert(key, bytes);
+    }
+  }
+
+  fn remove(&mut self, key: &K) {
+    if let Some(entry) = self.small.remove(key) {
+      self.small_bytes -= entry.bytes;
+    } else if let Some(entry) = self.main.remove(key) {
+      self.main_bytes -= entry.bytes;
+    }
+  }
+
+  fn evict(&mut self) -> Option<K> {
+    loop {
+      if self.evict_small() {
+        let (key, entry) = self.small.pop_front()?;
+        self.small_bytes -= entry.bytes;
+        if entry.frequency.load(Ordering::Relaxed) > 0 {
+          // Used again while in the small queue so it gets promoted
+          self.main_bytes += entry.bytes;
+          self.main.insert(key, S3Entry::new(entry.bytes));
+        } else {
+          self.remember(key.clone(), entry.bytes);
+          return Some(key)
+        }
+      } else {
+        let (key, entry) = self.main.pop_front()?;
+        let freq = entry.frequency.load(Ordering::Relaxed);
+        if freq > 0 {
+          entry.frequency.store(freq - 1, Ordering::Relaxed);
+          self.main.insert(key, entry);
+        } else {
+          self.main_bytes -= entry.bytes;
+          return Some(key)
+        }
+      }
+    }
+  }
+
+  // Only the queue that would be looked at, what comes out of it depends on the
+  // frequencies
+  fn peek(&self) -> Option<&K> {
+    let queue = if self.evict_small() { &self.small } else { &self.main };
+    queue.front().map(|(key, _)| key)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::MultiCache;
+  use super::S3Fifo;
+  use std::sync::Arc;
+
+  #[test]
+  fn scan_resistant() {
+    let cache = MultiCache::with_policy(1000, S3Fifo::new());
+
+    for i in 0..5 {
+      cache.put(i, i, 100);
+      cache.get(&i);
+    }
+    for i in 100..200 {
+      cache.put(i, i, 100);
+    }
+
+    for i in 0..5 {
+      assert_eq!(cache.get(&i), Some(Arc::new(i)));
+    }
+  }
+
+  #[test]
+  fn ghosts_go_to_main() {
+    let cache = MultiCache::with_policy(1000, S3Fifo::new());
+
+    for i in 0..11 {
+      cache.put(i, i, 100);
+    }
+    assert_eq!(cache.get(&0), None);
+
+    // Inserted again right after being evicted so it skips the small queue
+    cache.put(0, 0, 100);
+    for i in 100..110 {
+      cache.put(i, i, 100);
+    }
+    assert_eq!(cache.get(&0), Some(Arc::new(0)));
+  }
+}
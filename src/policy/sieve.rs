@@ -0,0 +1,210 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicBool, Ordering};
+use super::EvictionPolicy;
+
+struct SieveNode<K> {
+  key: K,
+  older: Option<usize>,
+  newer: Option<usize>,
+  visited: AtomicBool,
+}
+
+/// SIEVE eviction. Entries are kept in insertion order and a hit only marks the entry
+/// as visited without moving it. To evict, a hand walks from the oldest entries
+/// towards the newest clearing the visited mark of every entry it passes and evicts
+/// the first one that wasn't visited, remembering where it stopped for the next time.
+///
+/// Since hits don't reorder anything they are recorded with atomics and `get` only
+/// needs a shared lock on the cache.
+pub struct Sieve<K> {
+  nodes: Vec<Option<SieveNode<K>>>,
+  free: Vec<usize>,
+  index: HashMap<K,usize>,
+  newest: Option<usize>,
+  oldest: Option<usize>,
+  hand: Option<usize>,
+}
+
+impl<K: Hash+Eq> Sieve<K> {
+  pub fn new() -> Sieve<K> {
+    Sieve {
+      nodes: Vec::new(),
+      free: Vec::new(),
+      index: HashMap::new(),
+      newest: None,
+      oldest: None,
+      hand: None,
+    }
+  }
+
+  fn node(&self, idx: usize) -> &SieveNode<K> {
+    self.nodes[idx].as_ref().unwrap()
+  }
+
+  fn node_mut(&mut self, idx: usize) -> &mut SieveNode<K> {
+    self.nodes[idx].as_mut().unwrap()
+  }
+
+  fn unlink(&mut self, idx: usize) -> K {
+    let node = self.nodes[idx].take().unwrap();
+    match node.older {
+      None => self.oldest = node.newer,
+      Some(older) => self.node_mut(older).newer = node.newer,
+    }
+    match node.newer {
+      None => self.newest = node.older,
+      Some(newer) => self.node_mut(newer).older = node.older,
+    }
+    if self.hand == Some(idx) {
+      self.hand = node.newer;
+    }
+    self.free.push(idx);
+    node.key
+  }
+}
+
+impl<K: Hash+Eq> Default for Sieve<K> {
+  fn default() -> Sieve<K> {
+    Sieve::new()
+  }
+}
+
+impl<K: Hash+Eq+Clone> EvictionPolicy<K> for Sieve<K> {
+  const SHARED_HITS: bool = true;
+
+  fn insert(&mut self, key: &K, _bytes: usize) {
+    let node = SieveNode {
+      key: key.clone(),
+      older: self.newest,
+      newer: None,
+      visited: AtomicBool::new(false),
+    };
+    let idx = match self.free.pop() {
+      Some(idx) => { self.nodes[idx] = Some(node); idx },
+      None => { self.nodes.push(Some(node)); self.nodes.len() - 1 },
+    };
+
+    match self.newest {
+      None => self.oldest = Some(idx),
+      Some(newest) => self.node_mut(newest).newer = Some(idx),
+    }
+    self.newest = Some(idx);
+    self.index.insert(key.clone(), idx);
+  }
+
+  fn touch(&mut self, key: &K) {
+    self.touch_shared(key);
+  }
+
+  fn touch_shared(&self, key: &K) {
+    if let Some(idx) = self.index.get(key) {
+      self.node(*idx).visited.store(true, Ordering::Relaxed);
+    }
+  }
+
+  // Sizes don't matter here so the entry stays as it was
+  fn update(&mut self, key: &K, bytes: usize) {
+    if !self.index.contains_key(key) {
+      self.insert(key, bytes);
+    }
+  }
+
+  fn remove(&mut self, key: &K) {
+    if let Some(idx) = self.index.remove(key) {
+      self.unlink(idx);
+    }
+  }
+
+  fn evict(&mut self) -> Option<K> {
+    let mut idx = self.hand.or(self.oldest)?;
+    while self.node(idx).visited.swap(false, Ordering::Relaxed) {
+      idx = self.node(idx).newer.or(self.oldest).unwrap();
+    }
+
+    // Unlinking moves the hand on to the next newer entry
+    self.hand = Some(idx);
+    let key = self.unlink(idx);
+    self.index.remove(&key);
+    Some(key)
+  }
+
+  fn peek(&self) -> Option<&K> {
+    // Walk like evict would but without clearing anything, if everything has been
+    // visited the hand ends up evicting where it started
+    let start = self.hand.or(self.oldest)?;
+    let mut idx = start;
+    while self.node(idx).visited.load(Ordering::Relaxed) {
+      idx = self.node(idx).newer.or(self.oldest).unwrap();
+      if idx == start {
+        break
+      }
+    }
+    Some(&self.node(idx).key)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::MultiCache;
+  use crate::policy::EvictionPolicy;
+  use super::Sieve;
+  use std::sync::Arc;
+  use std::thread;
+
+  #[test]
+  fn evicts_unvisited() {
+    let cache = MultiCache::with_policy(300, Sieve::new());
+
+    cache.put(0, 0, 100);
+    cache.put(1, 1, 100);
+    cache.put(2, 2, 100);
+    cache.get(&0);
+    cache.put(3, 3, 100);
+
+    assert!(!cache.contains_key(&1));
+
+    // The hand stays where it was so the newer unvisited entry goes next even though
+    // the older one is no longer visited
+    cache.put(4, 4, 100);
+    assert!(!cache.contains_key(&2));
+    assert!(cache.contains_key(&0));
+    assert!(cache.contains_key(&3));
+  }
+
+  #[test]
+  fn hand_moves_on() {
+    let mut sieve = Sieve::new();
+    for i in 0..4 {
+      sieve.insert(&i, 100);
+    }
+    sieve.touch(&0);
+    sieve.touch(&1);
+
+    assert_eq!(sieve.evict(), Some(2));
+    assert_eq!(sieve.evict(), Some(3));
+    assert_eq!(sieve.evict(), Some(0));
+    assert_eq!(sieve.evict(), Some(1));
+    assert_eq!(sieve.evict(), None);
+  }
+
+  #[test]
+  fn shared_gets() {
+    let cache = Arc::new(MultiCache::with_policy(1000, Sieve::new()));
+    for i in 0..10 {
+      cache.put(i, i, 100);
+    }
+
+    let threads: Vec<_> = (0..4).map(|_| {
+      let cache = cache.clone();
+      thread::spawn(move || {
+        for i in 0..10 {
+          assert_eq!(cache.get(&i), Some(Arc::new(i)));
+        }
+      })
+    }).collect();
+    for thread in threads {
+      thread.join().unwrap();
+    }
+  }
+}
@@ -0,0 +1,333 @@
+use linked_hash_map::LinkedHashMap;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash};
+use super::{resize, EvictionPolicy};
+
+const SKETCH_DEPTH: usize = 4;
+const SKETCH_MAX_COUNT: u8 = 15;
+
+// Count-min sketch with 4-bit saturating counters that get halved every sample_size
+// increments so that old popularity fades away
+struct FrequencySketch {
+  table: Vec<u8>,
+  width: usize,
+  additions: usize,
+  sample_size: usize,
+  hasher: RandomState,
+}
+
+impl FrequencySketch {
+  fn new(expected_entries: usize) -> FrequencySketch {
+    let width = expected_entries.max(16).next_power_of_two();
+    FrequencySketch {
+      table: vec![0; width * SKETCH_DEPTH],
+      width,
+      additions: 0,
+      sample_size: width * 10,
+      hasher: RandomState::new(),
+    }
+  }
+
+  fn indexes<K: Hash>(&self, key: &K) -> [usize; SKETCH_DEPTH] {
+    let hash = self.hasher.hash_one(key);
+    let h1 = hash as usize;
+    let h2 = ((hash >> 32) as usize) | 1;
+    let mut indexes = [0; SKETCH_DEPTH];
+    for (row, index) in indexes.iter_mut().enumerate() {
+      let col = h1.wrapping_add(row.wrapping_mul(h2)) & (self.width - 1);
+      *index = row * self.width + col;
+    }
+    indexes
+  }
+
+  fn frequency<K: Hash>(&self, key: &K) -> u8 {
+    self.indexes(key).iter().map(|&i| self.table[i]).min().unwrap_or(0)
+  }
+
+  fn increment<K: Hash>(&mut self, key: &K) {
+    let mut added = false;
+    for i in self.indexes(key).iter() {
+      if self.table[*i] < SKETCH_MAX_COUNT {
+        self.table[*i] += 1;
+        added = true;
+      }
+    }
+
+    if added {
+      self.additions += 1;
+      if self.additions >= self.sample_size {
+        for count in self.table.iter_mut() {
+          *count /= 2;
+        }
+        self.additions /= 2;
+      }
+    }
+  }
+}
+
+// Where the next entry to be evicted comes from
+enum Victim {
+  Candidate,
+  Main,
+  Window,
+}
+
+/// Window TinyLFU eviction. New entries go into a small LRU window and once they fall
+/// out of it they only get into the main segmented LRU region if their estimated
+/// access frequency is higher than the one of the entry that would have to be evicted
+/// to make room for them. This keeps a frequently used set of entries from being
+/// flushed out by large scans of entries that are only ever used once.
+///
+/// Sizes are accounted in bytes, the window takes 1% of the cache and the protected
+/// part of the main region 80% of the rest. The newest entry always stays in the
+/// window even if it's larger than that so it gets a chance to be used again before
+/// having to compete for admission.
+pub struct TinyLfu<K> {
+  sketch: FrequencySketch,
+  window: LinkedHashMap<K,usize>,
+  candidates: LinkedHashMap<K,usize>,
+  probation: LinkedHashMap<K,usize>,
+  protected: LinkedHashMap<K,usize>,
+  window_bytes: usize,
+  candidate_bytes: usize,
+  probation_bytes: usize,
+  protected_bytes: usize,
+  window_max: usize,
+  protected_max: usize,
+  capacity: usize,
+}
+
+impl<K: Hash+Eq> TinyLfu<K> {
+  /// Create a new policy, the expected number of entries in the cache is used to size
+  /// the frequency sketch
+  pub fn new(expected_entries: usize) -> TinyLfu<K> {
+    TinyLfu {
+      sketch: FrequencySketch::new(expected_entries),
+      window: LinkedHashMap::new(),
+      candidates: LinkedHashMap::new(),
+      probation: LinkedHashMap::new(),
+      protected: LinkedHashMap::new(),
+      window_bytes: 0,
+      candidate_bytes: 0,
+      probation_bytes: 0,
+      protected_bytes: 0,
+      window_max: 0,
+      protected_max: 0,
+      capacity: 0,
+    }
+  }
+
+  fn totalsize(&self) -> usize {
+    self.window_bytes + self.candidate_bytes + self.probation_bytes + self.protected_bytes
+  }
+
+  // Once the cache is back under its budget whatever is waiting to be admitted fits
+  fn admit_if_room(&mut self) {
+    if self.totalsize() <= self.capacity {
+      while let Some((key, bytes)) = self.candidates.pop_front() {
+        self.candidate_bytes -= bytes;
+        self.probation_bytes += bytes;
+        self.probation.insert(key, bytes);
+      }
+    }
+  }
+
+  fn next_victim(&self) -> Victim {
+    let victim = self.probation.front().or_else(|| self.protected.front())
+      .map(|(key, _)| self.sketch.frequency(key));
+    let candidate = self.candidates.front().map(|(key, _)| self.sketch.frequency(key));
+
+    match (candidate, victim) {
+      // The candidate only gets in if it's been used more than the victim
+      (Some(cfreq), Some(vfreq)) if cfreq > vfreq => Victim::Main,
+      (Some(_), _) => Victim::Candidate,
+      (None, Some(_)) => Victim::Main,
+      (None, None) => Victim::Window,
+    }
+  }
+
+  // Move the least recently used window entries out to wait for admission
+  fn spill_window(&mut self) {
+    while self.window_bytes > self.window_max && self.window.len() > 1 {
+      match self.window.pop_front() {
+        None => break,
+        Some((key, bytes)) => {
+          self.window_bytes -= bytes;
+          self.candidate_bytes += bytes;
+          self.candidates.insert(key, bytes);
+        }
+      }
+    }
+  }
+
+  // Demote the least recently used protected entries back to probation
+  fn demote_protected(&mut self) {
+    while self.protected_bytes > self.protected_max && self.protected.len() > 1 {
+      if let Some((key, bytes)) = self.protected.pop_front() {
+        self.protected_bytes -= bytes;
+        self.probation_bytes += bytes;
+        self.probation.insert(key, bytes);
+      }
+    }
+  }
+
+  fn pop_main(&mut self) -> Option<K> {
+    if let Some((key, bytes)) = self.probation.pop_front() {
+      self.probation_bytes -= bytes;
+      Some(key)
+    } else if let Some((key, bytes)) = self.protected.pop_front() {
+      self.protected_bytes -= bytes;
+      Some(key)
+    } else {
+      None
+    }
+  }
+}
+
+impl<K: Hash+Eq+Clone> EvictionPolicy<K> for TinyLfu<K> {
+  fn set_capacity(&mut self, maxsize: usize) {
+    self.capacity = maxsize;
+    self.window_max = maxsize / 100;
+    self.protected_max = (maxsize - self.window_max) / 10 * 8;
+  }
+
+  fn insert(&mut self, key: &K, bytes: usize) {
+    self.sketch.increment(key);
+    self.window.insert(key.clone(), bytes);
+    self.window_bytes += bytes;
+    self.spill_window();
+    self.admit_if_room();
+  }
+
+  fn touch(&mut self, key: &K) {
+    self.sketch.increment(key);
+
+    if self.window.get_refresh(key).is_some() || self.candidates.get_refresh(key).is_some() {
+      return
+    }
+
+    if let Some(bytes) = self.probation.remove(key) {
+      self.probation_bytes -= bytes;
+      self.protected_bytes += bytes;
+      self.protected.insert(key.clone(), bytes);
+      self.demote_protected();
+    } else {
+      self.protected.get_refresh(key);
+    }
+  }
+
+  // Counts as a use and stays in whichever part it was in
+  fn update(&mut self, key: &K, bytes: usize) {
+    if resize(&mut self.window, &mut self.window_bytes, key, bytes) {
+      self.spill_window();
+    } else if resize(&mut self.protected, &mut self.protected_bytes, key, bytes) {
+      self.demote_protected();
+    } else if !resize(&mut self.candidates, &mut self.candidate_bytes, key, bytes) &&
+      !resize(&mut self.probation, &mut self.probation_bytes, key, bytes) {
+      return self.insert(key, bytes)
+    }
+    self.sketch.increment(key);
+    self.admit_if_room();
+  }
+
+  fn remove(&mut self, key: &K) {
+    if let Some(bytes) = self.window.remove(key) {
+      self.window_bytes -= bytes;
+    } else if let Some(bytes) = self.candidates.remove(key) {
+      self.candidate_bytes -= bytes;
+    } else if let Some(bytes) = self.probation.remove(key) {
+      self.probation_bytes -= bytes;
+    } else if let Some(bytes) = self.protected.remove(key) {
+      self.protected_bytes -= bytes;
+    }
+  }
+
+  fn evict(&mut self) -> Option<K> {
+    let evicted = match self.next_victim() {
+      Victim::Candidate => {
+        self.candidates.pop_front().map(|(key, bytes)| {
+          self.candidate_bytes -= bytes;
+          key
+        })
+      },
+      Victim::Main => self.pop_main(),
+      Victim::Window => {
+        self.window.pop_front().map(|(key, bytes)| {
+          self.window_bytes -= bytes;
+          key
+        })
+      },
+    };
+
+    self.admit_if_room();
+    evicted
+  }
+
+  fn peek(&self) -> Option<&K> {
+    match self.next_victim() {
+      Victim::Candidate => self.candidates.front(),
+      Victim::Main => self.probation.front().or_else(|| self.protected.front()),
+      Victim::Window => self.window.front(),
+    }.map(|(key, _)| key)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::MultiCache;
+  use super::TinyLfu;
+  use std::sync::Arc;
+
+  #[test]
+  fn scan_resistant() {
+    let cache = MultiCache::with_policy(1000, TinyLfu::new(100));
+
+    for i in 0..10 {
+      cache.put(i, i, 100);
+    }
+    for _ in 0..5 {
+      for i in 0..5 {
+        cache.get(&i);
+      }
+    }
+    for i in 100..200 {
+      cache.put(i, i, 100);
+    }
+
+    for i in 0..5 {
+      assert_eq!(cache.get(&i), Some(Arc::new(i)));
+    }
+  }
+
+  #[test]
+  fn frequent_newcomer_admitted() {
+    let cache = MultiCache::with_policy(300, TinyLfu::new(100));
+
+    cache.put(0, 0, 100);
+    cache.put(1, 1, 100);
+    cache.put(2, 2, 100);
+    cache.put(3, 3, 100);
+    assert_eq!(cache.get(&2), None);
+
+    // Used again while in the window so it wins against the main region once it's out
+    for _ in 0..5 {
+      cache.get(&3);
+    }
+    cache.put(4, 4, 100);
+    assert_eq!(cache.get(&3), Some(Arc::new(3)));
+    assert_eq!(cache.get(&0), None);
+  }
+
+  #[test]
+  fn window_keeps_newest() {
+    let cache = MultiCache::with_policy(1000, TinyLfu::new(100));
+
+    // Every entry is larger than the 1% window
+    for i in 0..10 {
+      cache.put(i, i, 100);
+    }
+    cache.put(10, 10, 100);
+    assert_eq!(cache.get(&10), Some(Arc::new(10)));
+    assert_eq!(cache.totalsize(), 1000);
+  }
+}
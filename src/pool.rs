@@ -0,0 +1,329 @@
+use std::fmt;
+use std::ptr;
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+// What a pool needs from the caches that draw from it
+pub(crate) trait PoolMember: Send + Sync {
+  // Bytes currently taken up by the cache
+  fn usage(&self) -> usize;
+  // Access tick of the element the cache would evict next
+  fn next_victim(&self) -> Option<u64>;
+  // Evict a single element, returning whether there was one
+  fn evict_one(&self) -> bool;
+}
+
+/// How a memory pool picks which of its caches to evict from when it goes over budget
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PoolEviction {
+  /// Evict from the cache whose next victim was used the longest time ago, so the pool
+  /// as a whole behaves close to a single cache
+  Recency,
+  /// Evict from the cache using the most bytes relative to its weight
+  Weighted,
+}
+
+struct PoolEntry {
+  name: String,
+  weight: u32,
+  minsize: usize,
+  member: Weak<dyn PoolMember>,
+}
+
+/// A byte budget shared by several caches. Each cache created in the pool with
+/// `MultiCache::in_pool` takes up space from it and whenever the total goes over the
+/// budget elements get evicted from whichever cache the pool picks, not necessarily
+/// the one that was just added to.
+///
+/// Pools can be split into a tree of child pools, each with its own hard maximum and
+/// a guaranteed minimum. Space taken up in a child counts against all its ancestors.
+/// A child that pushes its parent over budget evicts its own elements for as long as
+/// it's above its minimum and only then does the parent evict from the other children
+/// that are above theirs, so a single busy child can't push everyone else out.
+pub struct MemoryPool {
+  maxsize: usize,
+  minsize: usize,
+  usage: AtomicUsize,
+  ticks: AtomicU64,
+  eviction: PoolEviction,
+  members: Mutex<Vec<PoolEntry>>,
+  parent: Option<Arc<MemoryPool>>,
+}
+
+impl fmt::Debug for MemoryPool {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{{ {} usage, {} minsize, {} maxsize }}",
+      self.usage(), self.minsize, self.maxsize)
+  }
+}
+
+impl MemoryPool {
+  /// Create a new pool which will at most hold a total of bytesize in elements across
+  /// all of its caches
+  pub fn new(bytesize: usize, eviction: PoolEviction) -> Arc<MemoryPool> {
+    Arc::new(MemoryPool {
+      maxsize: bytesize,
+      minsize: 0,
+      usage: AtomicUsize::new(0),
+      ticks: AtomicU64::new(0),
+      eviction,
+      members: Mutex::new(Vec::new()),
+      parent: None,
+    })
+  }
+
+  /// Create a child pool that will at most hold maxsize bytes and that won't be evicted
+  /// from to make room for its siblings while it holds minsize bytes or less
+  pub fn child(self: &Arc<Self>, name: &str, minsize: usize, maxsize: usize) -> Arc<MemoryPool> {
+    let child = Arc::new(MemoryPool {
+      maxsize,
+      minsize,
+      usage: AtomicUsize::new(0),
+      ticks: AtomicU64::new(0),
+      eviction: self.eviction,
+      members: Mutex::new(Vec::new()),
+      parent: Some(self.clone()),
+    });
+    self.members.lock().unwrap().push(PoolEntry {
+      name: name.to_string(),
+      weight: 1,
+      minsize,
+      member: Arc::downgrade(&child) as Weak<dyn PoolMember>,
+    });
+    child
+  }
+
+  /// The total byte budget of the pool
+  pub fn maxsize(&self) -> usize {
+    self.maxsize
+  }
+
+  /// How many bytes the pool is guaranteed to be able to hold
+  pub fn minsize(&self) -> usize {
+    self.minsize
+  }
+
+  /// How many bytes are currently taken up by all the caches in the pool
+  pub fn usage(&self) -> usize {
+    self.usage.load(Ordering::Relaxed)
+  }
+
+  /// How many bytes are currently taken up by each of the caches and child pools in the
+  /// pool by name
+  pub fn cache_usage(&self) -> Vec<(String, usize)> {
+    self.members.lock().unwrap().iter().filter_map(|entry| {
+      entry.member.upgrade().map(|member| (entry.name.clone(), member.usage()))
+    }).collect()
+  }
+
+  pub(crate) fn attach(&self, name: &str, weight: u32, member: Weak<dyn PoolMember>) {
+    self.members.lock().unwrap().push(PoolEntry {
+      name: name.to_string(),
+      weight,
+      minsize: 0,
+      member,
+    });
+  }
+
+  // Access order is kept by the root so that it's comparable across the whole tree
+  pub(crate) fn tick(&self) -> u64 {
+    match self.parent {
+      Some(ref parent) => parent.tick(),
+      None => self.ticks.fetch_add(1, Ordering::Relaxed) + 1,
+    }
+  }
+
+  pub(crate) fn charge(&self, bytes: usize) {
+    self.usage.fetch_add(bytes, Ordering::Relaxed);
+    if let Some(ref parent) = self.parent {
+      parent.charge(bytes);
+    }
+  }
+
+  pub(crate) fn release(&self, bytes: usize) {
+    self.usage.fetch_sub(bytes, Ordering::Relaxed);
+    if let Some(ref parent) = self.parent {
+      parent.release(bytes);
+    }
+  }
+
+  // Evict until this pool and all its ancestors are back within budget, must be called
+  // with none of the caches locked
+  pub(crate) fn reclaim(&self) {
+    self.reclaim_from(None)
+  }
+
+  // Same as reclaim with the child pool whose elements pushed this one over budget
+  fn reclaim_from(&self, source: Option<&MemoryPool>) {
+    while self.usage() > self.maxsize {
+      if !self.pick_victim(source).is_some_and(|member| member.evict_one()) {
+        break
+      }
+    }
+    if let Some(ref parent) = self.parent {
+      parent.reclaim_from(Some(self));
+    }
+  }
+
+  fn pick_victim(&self, source: Option<&MemoryPool>) -> Option<Arc<dyn PoolMember>> {
+    let mut members = self.members.lock().unwrap();
+    members.retain(|entry| entry.member.strong_count() > 0);
+
+    let live: Vec<_> = members.iter().filter_map(|entry| {
+      let member = entry.member.upgrade()?;
+      let usage = member.usage();
+      if usage > 0 { Some((entry, usage, member)) } else { None }
+    }).collect();
+    // The child that went over evicts from itself first while it's above its minimum
+    let source = source.and_then(|source| live.iter().find(|(entry, usage, _)| {
+      ptr::addr_eq(entry.member.as_ptr(), source) && *usage > entry.minsize
+    }));
+    if let Some((_, _, member)) = source {
+      return Some(member.clone())
+    }
+    // Only take from members that are above their guaranteed minimum unless there's
+    // nothing else left
+    let above_min = live.iter().any(|(entry, usage, _)| *usage > entry.minsize);
+    let candidates = live.into_iter().filter(|(entry, usage, _)| !above_min || *usage > entry.minsize);
+
+    let victim = match self.eviction {
+      // Members that can't tell what they would evict next go last
+      PoolEviction::Recency => candidates.min_by_key(|(_, _, member)| member.next_victim().unwrap_or(u64::MAX)),
+      PoolEviction::Weighted => candidates.max_by_key(|(entry, usage, _)| {
+        let excess = usage.saturating_sub(entry.minsize) as u128;
+        excess * 1_000_000 / entry.weight.max(1) as u128
+      }),
+    };
+    victim.map(|(_, _, member)| member)
+  }
+}
+
+impl PoolMember for MemoryPool {
+  fn usage(&self) -> usize {
+    self.usage()
+  }
+
+  fn next_victim(&self) -> Option<u64> {
+    self.pick_victim(None)?.next_victim()
+  }
+
+  fn evict_one(&self) -> bool {
+    self.pick_victim(None).is_some_and(|member| member.evict_one())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::MultiCache;
+  use super::{MemoryPool, PoolEviction};
+  use std::sync::Arc;
+
+  #[test]
+  fn shares_budget() {
+    let pool = MemoryPool::new(300, PoolEviction::Recency);
+    let images = MultiCache::in_pool(&pool, "images", 1);
+    let thumbs = MultiCache::in_pool(&pool, "thumbs", 1);
+
+    images.put(0, 0, 100);
+    images.put(1, 1, 100);
+    thumbs.put(0, 0, 100);
+    images.get(&0);
+    thumbs.put(1, 1, 100);
+
+    assert_eq!(images.get(&0), Some(Arc::new(0)));
+    assert_eq!(images.get(&1), None);
+    assert_eq!(thumbs.get(&0), Some(Arc::new(0)));
+    assert_eq!(thumbs.get(&1), Some(Arc::new(1)));
+    assert_eq!(pool.usage(), 300);
+    assert_eq!(pool.cache_usage(), vec![
+      ("images".to_string(), 100),
+      ("thumbs".to_string(), 200),
+    ]);
+  }
+
+  #[test]
+  fn evicts_by_weight() {
+    let pool = MemoryPool::new(400, PoolEviction::Weighted);
+    let images = MultiCache::in_pool(&pool, "images", 3);
+    let thumbs = MultiCache::in_pool(&pool, "thumbs", 1);
+
+    images.put(0, 0, 100);
+    images.put(1, 1, 100);
+    thumbs.put(0, 0, 100);
+    thumbs.put(1, 1, 100);
+    images.put(2, 2, 100);
+
+    // The images cache uses more bytes but less of them relative to its weight
+    assert!(images.contains_key(&0));
+    assert!(!thumbs.contains_key(&0));
+    assert!(thumbs.contains_key(&1));
+    assert_eq!(pool.usage(), 400);
+  }
+
+  #[test]
+  fn releases_on_drop() {
+    let pool = MemoryPool::new(300, PoolEviction::Recency);
+    let images = MultiCache::in_pool(&pool, "images", 1);
+    let thumbs = MultiCache::in_pool(&pool, "thumbs", 1);
+
+    images.put(0, 0, 100);
+    thumbs.put(0, 0, 100);
+    drop(images);
+
+    assert_eq!(pool.usage(), 100);
+    assert_eq!(pool.cache_usage(), vec![("thumbs".to_string(), 100)]);
+  }
+
+  #[test]
+  fn keeps_child_minimum() {
+    let root = MemoryPool::new(1000, PoolEviction::Recency);
+    let quiet = MultiCache::in_pool(&root.child("quiet", 300, 1000), "metadata", 1);
+    let noisy = MultiCache::in_pool(&root.child("noisy", 300, 1000), "images", 1);
+
+    for i in 0..5 {
+      quiet.put(i, i, 100);
+    }
+    for i in 0..10 {
+      noisy.put(i, i, 100);
+    }
+
+    // The noisy child only evicts its own elements while it's above its minimum
+    assert_eq!(root.usage(), 1000);
+    assert_eq!(root.cache_usage(), vec![
+      ("quiet".to_string(), 500),
+      ("noisy".to_string(), 500),
+    ]);
+    for i in 0..5 {
+      assert!(quiet.contains_key(&i));
+    }
+
+    // Once it's down to its minimum the siblings above theirs make room
+    noisy.clear();
+    for i in 5..10 {
+      quiet.put(i, i, 100);
+    }
+    for i in 0..3 {
+      noisy.put(i, i, 100);
+    }
+    assert_eq!(root.cache_usage(), vec![
+      ("quiet".to_string(), 700),
+      ("noisy".to_string(), 300),
+    ]);
+    assert!(!quiet.contains_key(&0));
+  }
+
+  #[test]
+  fn enforces_child_maximum() {
+    let root = MemoryPool::new(1000, PoolEviction::Recency);
+    let tenant = root.child("tenant", 0, 200);
+    let cache = MultiCache::in_pool(&tenant, "images", 1);
+
+    cache.put(0, 0, 100);
+    cache.put(1, 1, 100);
+    cache.put(2, 2, 100);
+
+    assert!(!cache.contains_key(&0));
+    assert_eq!(tenant.usage(), 200);
+    assert_eq!(root.usage(), 200);
+  }
+}
@@ -0,0 +1,323 @@
+use std::fmt;
+use std::fs;
+use std::hash::Hash;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use crate::policy::EvictionPolicy;
+use crate::{MultiCache, ShardedMultiCache};
+
+// Share of the free memory the caches are allowed to grow into
+const HEADROOM_SHARE: f64 = 0.5;
+
+/// A cache whose byte budget can be adjusted by a `PressureController`
+pub trait Resizable: Send + Sync {
+  /// Bytes taken up by the elements in the cache
+  fn totalsize(&self) -> usize;
+  /// Change how many bytes the cache can hold
+  fn set_max_bytes(&self, bytesize: usize);
+}
+
+impl<K,V,P> Resizable for MultiCache<K,V,P>
+where K: Hash+Eq+Send+Sync, V: Send+Sync, P: EvictionPolicy<K>+Send+Sync {
+  fn totalsize(&self) -> usize { self.totalsize() }
+  fn set_max_bytes(&self, bytesize: usize) { self.set_max_bytes(bytesize) }
+}
+
+impl<K,V,P> Resizable for ShardedMultiCache<K,V,P>
+where K: Hash+Eq+Send+Sync, V: Send+Sync, P: EvictionPolicy<K>+Send+Sync {
+  fn totalsize(&self) -> usize { self.totalsize() }
+  fn set_max_bytes(&self, bytesize: usize) { self.set_max_bytes(bytesize) }
+}
+
+/// Where to read the memory signals from. The default is the real files of a Linux
+/// system, any of them can be pointed somewhere else for testing or set to None to be
+/// ignored.
+#[derive(Debug, Clone)]
+pub struct MemorySignals {
+  /// File with a `MemAvailable` line like `/proc/meminfo`
+  pub meminfo: Option<PathBuf>,
+  /// Directory with the cgroup v2 `memory.current` and `memory.max` files, by default
+  /// the one of the cgroup the process is in
+  pub cgroup: Option<PathBuf>,
+  /// File with pressure stall information like `/proc/pressure/memory`
+  pub pressure: Option<PathBuf>,
+}
+
+impl Default for MemorySignals {
+  fn default() -> MemorySignals {
+    MemorySignals {
+      meminfo: Some(PathBuf::from("/proc/meminfo")),
+      cgroup: own_cgroup(Path::new("/proc/self/cgroup"), Path::new("/sys/fs/cgroup")),
+      pressure: Some(PathBuf::from("/proc/pressure/memory")),
+    }
+  }
+}
+
+/// What was read from the memory signals, with None for the ones that weren't there
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct MemoryReading {
+  /// Bytes of memory available to start new work without swapping
+  pub available: Option<u64>,
+  /// Bytes used by the cgroup
+  pub cgroup_current: Option<u64>,
+  /// Byte limit of the cgroup, None if it has none
+  pub cgroup_max: Option<u64>,
+  /// Percentage of the last 10 seconds some tasks were stalled waiting on memory
+  pub pressure: Option<f64>,
+}
+
+impl MemoryReading {
+  /// Bytes that can still be used before running out of memory either in the system or
+  /// in the cgroup, None if neither could be read
+  pub fn headroom(&self) -> Option<u64> {
+    let cgroup = match (self.cgroup_current, self.cgroup_max) {
+      (Some(current), Some(max)) => Some(max.saturating_sub(current)),
+      _ => None,
+    };
+    match (self.available, cgroup) {
+      (Some(a), Some(b)) => Some(a.min(b)),
+      (a, b) => a.or(b),
+    }
+  }
+}
+
+// The directory of the cgroup v2 a process is in, from the `0::` line of its
+// /proc/<pid>/cgroup file. None if it's not in one, like with cgroup v1.
+fn own_cgroup(proc_cgroup: &Path, root: &Path) -> Option<PathBuf> {
+  let cgroups = fs::read_to_string(proc_cgroup).ok()?;
+  let path = cgroups.lines().find_map(|line| line.strip_prefix("0::"))?;
+  Some(root.join(path.trim_start_matches('/')))
+}
+
+fn read_number(path: &Path) -> Option<u64> {
+  fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+impl MemorySignals {
+  /// Read all the signals, missing or unreadable files leave their value as None
+  pub fn read(&self) -> MemoryReading {
+    let available = self.meminfo.as_ref().and_then(|path| {
+      let meminfo = fs::read_to_string(path).ok()?;
+      let line = meminfo.lines().find(|line| line.starts_with("MemAvailable:"))?;
+      let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+      Some(kb * 1024)
+    });
+    let cgroup_current = self.cgroup.as_ref().and_then(|dir| read_number(&dir.join("memory.current")));
+    // A limit of "max" means there isn't one
+    let cgroup_max = self.cgroup.as_ref().and_then(|dir| read_number(&dir.join("memory.max")));
+    let pressure = self.pressure.as_ref().and_then(|path| {
+      let pressure = fs::read_to_string(path).ok()?;
+      let line = pressure.lines().find(|line| line.starts_with("some "))?;
+      let avg10 = line.split_whitespace().find_map(|field| field.strip_prefix("avg10="))?;
+      avg10.parse().ok()
+    });
+
+    MemoryReading {
+      available,
+      cgroup_current,
+      cgroup_max,
+      pressure,
+    }
+  }
+}
+
+struct Managed {
+  cache: Weak<dyn Resizable>,
+  floor: usize,
+  ceiling: usize,
+}
+
+/// Adjusts the byte budget of a set of caches to how much memory is left on the
+/// machine. Each time it runs the caches are allowed to grow into half the memory that
+/// is still free, less when the system reports it's stalling on memory, and the total
+/// is split between them staying within each one's floor and ceiling.
+pub struct PressureController {
+  signals: MemorySignals,
+  caches: Mutex<Vec<Managed>>,
+}
+
+impl fmt::Debug for PressureController {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{{ {:?}, {} caches }}", self.signals, self.caches.lock().unwrap().len())
+  }
+}
+
+impl PressureController {
+  /// Create a controller reading from the given signals
+  pub fn new(signals: MemorySignals) -> Arc<PressureController> {
+    Arc::new(PressureController {
+      signals,
+      caches: Mutex::new(Vec::new()),
+    })
+  }
+
+  /// Have the controller size a cache, never going below floor or above ceiling bytes.
+  /// The controller doesn't keep the cache alive.
+  pub fn add<C>(&self, cache: &Arc<C>, floor: usize, ceiling: usize)
+  where C: Resizable + 'static {
+    self.caches.lock().unwrap().push(Managed {
+      cache: Arc::downgrade(cache) as Weak<dyn Resizable>,
+      floor,
+      ceiling: ceiling.max(floor),
+    });
+  }
+
+  /// Read the signals and resize the caches once, returning the total budget given to
+  /// them or None if no signal could be read and nothing was changed
+  pub fn adjust(&self) -> Option<usize> {
+    let reading = self.signals.read();
+    let headroom = reading.headroom()?;
+
+    let mut caches = self.caches.lock().unwrap();
+    caches.retain(|managed| managed.cache.strong_count() > 0);
+    let live: Vec<_> = caches.iter().filter_map(|managed| {
+      managed.cache.upgrade().map(|cache| (cache, managed.floor, managed.ceiling))
+    }).collect();
+    drop(caches);
+
+    let usage: usize = live.iter().map(|(cache, _, _)| cache.totalsize()).sum();
+    let mut target = usage as f64 + headroom as f64 * HEADROOM_SHARE;
+    if let Some(pressure) = reading.pressure {
+      target *= 1.0 - (pressure / 100.0).clamp(0.0, 1.0);
+    }
+
+    // Every cache sits at the same point between its floor and its ceiling
+    let floors: usize = live.iter().map(|(_, floor, _)| floor).sum();
+    let ceilings: usize = live.iter().map(|(_, _, ceiling)| ceiling).sum();
+    let share = if ceilings > floors {
+      ((target - floors as f64) / (ceilings - floors) as f64).clamp(0.0, 1.0)
+    } else {
+      1.0
+    };
+
+    let mut total = 0;
+    for (cache, floor, ceiling) in live {
+      let size = floor + ((ceiling - floor) as f64 * share) as usize;
+      cache.set_max_bytes(size);
+      total += size;
+    }
+    Some(total)
+  }
+
+  /// Adjust the caches every interval from a background thread, which stops once the
+  /// controller is dropped
+  pub fn start(self: &Arc<Self>, interval: Duration) -> JoinHandle<()> {
+    let controller = Arc::downgrade(self);
+    thread::spawn(move || {
+      while let Some(controller) = controller.upgrade() {
+        controller.adjust();
+        drop(controller);
+        thread::sleep(interval);
+      }
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::MultiCache;
+  use super::{own_cgroup, MemorySignals, PressureController};
+  use std::fs;
+  use std::path::{Path, PathBuf};
+  use std::sync::Arc;
+
+  // A directory for fixture files that gets removed at the end of the test
+  struct TempDir(PathBuf);
+
+  impl TempDir {
+    fn new(name: &str) -> TempDir {
+      let dir = std::env::temp_dir().join(format!("multicache-{}-{}", name, std::process::id()));
+      fs::create_dir_all(&dir).unwrap();
+      TempDir(dir)
+    }
+  }
+
+  impl Drop for TempDir {
+    fn drop(&mut self) {
+      let _ = fs::remove_dir_all(&self.0);
+    }
+  }
+
+  fn fixtures(dir: &TempDir, meminfo_kb: u64, current: u64, max: &str, avg10: &str) -> MemorySignals {
+    let dir = &dir.0;
+    fs::write(dir.join("meminfo"), format!(
+      "MemTotal:       16000000 kB\nMemFree:         1000000 kB\nMemAvailable:   {} kB\n", meminfo_kb)).unwrap();
+    fs::write(dir.join("memory.current"), format!("{}\n", current)).unwrap();
+    fs::write(dir.join("memory.max"), format!("{}\n", max)).unwrap();
+    fs::write(dir.join("pressure"), format!(
+      "some avg10={} avg60=0.00 avg300=0.00 total=0\nfull avg10=0.00 avg60=0.00 avg300=0.00 total=0\n", avg10)).unwrap();
+    MemorySignals {
+      meminfo: Some(dir.join("meminfo")),
+      cgroup: Some(dir.to_path_buf()),
+      pressure: Some(dir.join("pressure")),
+    }
+  }
+
+  #[test]
+  fn reads_signals() {
+    let dir = TempDir::new("reads");
+    let signals = fixtures(&dir, 1000, 5000, "max", "12.50");
+    let reading = signals.read();
+
+    assert_eq!(reading.available, Some(1024000));
+    assert_eq!(reading.cgroup_current, Some(5000));
+    assert_eq!(reading.cgroup_max, None);
+    assert_eq!(reading.pressure, Some(12.5));
+    assert_eq!(reading.headroom(), Some(1024000));
+  }
+
+  #[test]
+  fn sizes_caches() {
+    let images = Arc::new(MultiCache::new(1000));
+    let thumbs = Arc::new(MultiCache::<u32,u32>::new(1000));
+    for i in 0..10 {
+      images.put(i, i, 100);
+    }
+
+    // 1000 bytes of cache usage plus half of the 2000 left in the cgroup
+    let dir = TempDir::new("sizes");
+    let controller = PressureController::new(fixtures(&dir, 1000000, 8000, "10000", "0.00"));
+    controller.add(&images, 500, 2500);
+    controller.add(&thumbs, 500, 2500);
+    assert_eq!(controller.adjust(), Some(2000));
+    assert_eq!(images.maxsize(), 1000);
+    assert_eq!(thumbs.maxsize(), 1000);
+    assert_eq!(images.totalsize(), 1000);
+
+    // Under heavy pressure they go down to the floor
+    let controller = PressureController::new(fixtures(&dir, 1000000, 8000, "10000", "100.00"));
+    controller.add(&images, 500, 2500);
+    controller.add(&thumbs, 500, 2500);
+    assert_eq!(controller.adjust(), Some(1000));
+    assert_eq!(images.totalsize(), 500);
+  }
+
+  #[test]
+  fn finds_own_cgroup() {
+    let dir = TempDir::new("cgroup");
+    let root = Path::new("/sys/fs/cgroup");
+
+    fs::write(dir.0.join("cgroup"), "0::/system.slice/app.service\n").unwrap();
+    assert_eq!(own_cgroup(&dir.0.join("cgroup"), root), Some(root.join("system.slice/app.service")));
+    fs::write(dir.0.join("cgroup"), "0::/\n").unwrap();
+    assert_eq!(own_cgroup(&dir.0.join("cgroup"), root), Some(root.to_path_buf()));
+    fs::write(dir.0.join("cgroup"), "12:memory:/app\n1:name=systemd:/app\n").unwrap();
+    assert_eq!(own_cgroup(&dir.0.join("cgroup"), root), None);
+  }
+
+  #[test]
+  fn ignores_missing_signals() {
+    let cache = Arc::new(MultiCache::<u32,u32>::new(1000));
+    let controller = PressureController::new(MemorySignals {
+      meminfo: Some(PathBuf::from("/nonexistent/meminfo")),
+      cgroup: None,
+      pressure: None,
+    });
+    controller.add(&cache, 0, 5000);
+
+    assert_eq!(controller.adjust(), None);
+    assert_eq!(cache.maxsize(), 1000);
+  }
+}
@@ -0,0 +1,145 @@
+//! Rendering cache metrics in the Prometheus text exposition format, so they can be
+//! served from an existing metrics endpoint.
+//!
+//! ```rust
+//!  extern crate multicache;
+//!  use multicache::MultiCache;
+//!  use multicache::prometheus;
+//!
+//!  fn main() {
+//!    let images = MultiCache::new(200);
+//!    let thumbs = MultiCache::new(100);
+//!    images.put(0, 0, 100);
+//!    thumbs.put("0", 0, 10);
+//!
+//!    let text = prometheus::encode(&[("images", &images), ("thumbs", &thumbs)]);
+//!    assert!(text.contains("multicache_totalsize_bytes{cache=\"images\"} 100\n"));
+//!  }
+//! ```
+
+use std::fmt::Write;
+use std::hash::Hash;
+use crate::{CacheStats, MultiCache, ShardedMultiCache};
+
+/// Something that can report metrics about a cache
+pub trait MetricsSource {
+  /// Number of elements in the cache
+  fn entries(&self) -> usize;
+  /// Bytes taken up by the elements
+  fn totalsize(&self) -> usize;
+  /// Bytes the cache can hold
+  fn maxsize(&self) -> usize;
+  /// Hit, miss and removal counts
+  fn stats(&self) -> CacheStats;
+}
+
+impl<K: Hash+Eq,V,P> MetricsSource for MultiCache<K,V,P> {
+  fn entries(&self) -> usize { self.len() }
+  fn totalsize(&self) -> usize { self.totalsize() }
+  fn maxsize(&self) -> usize { self.maxsize() }
+  fn stats(&self) -> CacheStats { self.stats() }
+}
+
+impl<K: Hash+Eq,V,P> MetricsSource for ShardedMultiCache<K,V,P> {
+  fn entries(&self) -> usize { self.len() }
+  fn totalsize(&self) -> usize { self.totalsize() }
+  fn maxsize(&self) -> usize { self.maxsize() }
+  fn stats(&self) -> CacheStats { self.stats() }
+}
+
+struct Snapshot<'a> {
+  name: &'a str,
+  entries: usize,
+  totalsize: usize,
+  maxsize: usize,
+  stats: CacheStats,
+}
+
+// Backslashes, quotes and newlines need escaping in label values
+fn escape(value: &str) -> String {
+  value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn family<F>(out: &mut String, snapshots: &[Snapshot], name: &str, kind: &str, help: &str, value: F)
+where F: Fn(&Snapshot) -> u64 {
+  writeln!(out, "# HELP {} {}", name, help).unwrap();
+  writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+  for snapshot in snapshots {
+    writeln!(out, "{}{{cache=\"{}\"}} {}", name, escape(snapshot.name), value(snapshot)).unwrap();
+  }
+}
+
+/// Render the metrics of a list of caches by name, each becoming the cache label
+pub fn encode(caches: &[(&str, &dyn MetricsSource)]) -> String {
+  let snapshots: Vec<Snapshot> = caches.iter().map(|(name, cache)| Snapshot {
+    name,
+    entries: cache.entries(),
+    totalsize: cache.totalsize(),
+    maxsize: cache.maxsize(),
+    stats: cache.stats(),
+  }).collect();
+
+  let mut out = String::new();
+  family(&mut out, &snapshots, "multicache_entries", "gauge",
+    "Number of elements in the cache.", |s| s.entries as u64);
+  family(&mut out, &snapshots, "multicache_totalsize_bytes", "gauge",
+    "Bytes taken up by the elements in the cache.", |s| s.totalsize as u64);
+  family(&mut out, &snapshots, "multicache_maxsize_bytes", "gauge",
+    "Bytes the cache can hold.", |s| s.maxsize as u64);
+  family(&mut out, &snapshots, "multicache_peak_bytes", "gauge",
+    "Most bytes the cache has held at once.", |s| s.stats.peak_size as u64);
+  family(&mut out, &snapshots, "multicache_hits_total", "counter",
+    "Reads that found a value.", |s| s.stats.hits);
+  family(&mut out, &snapshots, "multicache_misses_total", "counter",
+    "Reads that didn't find a value.", |s| s.stats.misses);
+  family(&mut out, &snapshots, "multicache_inserts_total", "counter",
+    "Elements put in the cache.", |s| s.stats.inserts);
+  family(&mut out, &snapshots, "multicache_evicted_bytes_total", "counter",
+    "Bytes taken up by evicted elements.", |s| s.stats.evicted_bytes);
+
+  let name = "multicache_removals_total";
+  writeln!(out, "# HELP {} Elements removed from the cache by cause.", name).unwrap();
+  writeln!(out, "# TYPE {} counter", name).unwrap();
+  for snapshot in snapshots.iter() {
+    let causes = [
+      ("evicted", snapshot.stats.evictions),
+      ("replaced", snapshot.stats.replacements),
+      ("explicit", snapshot.stats.removals),
+      ("expired", snapshot.stats.expirations),
+      ("cleared", snapshot.stats.clears),
+    ];
+    for (cause, value) in causes.iter() {
+      writeln!(out, "{}{{cache=\"{}\",cause=\"{}\"}} {}",
+        name, escape(snapshot.name), cause, value).unwrap();
+    }
+  }
+  out
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::{MultiCache, ShardedMultiCache};
+  use super::encode;
+
+  #[test]
+  fn renders_caches() {
+    let images = MultiCache::new(200);
+    let thumbs = ShardedMultiCache::new(100, 2);
+    images.put(0, 0, 100);
+    images.put(1, 1, 100);
+    images.put(2, 2, 100);
+    images.get(&2);
+    thumbs.put(0, 0, 10);
+
+    let text = encode(&[("images", &images), ("thumbs \"small\"", &thumbs)]);
+    let lines: Vec<&str> = text.lines().collect();
+
+    assert!(lines.contains(&"# TYPE multicache_entries gauge"));
+    assert!(lines.contains(&"multicache_entries{cache=\"images\"} 2"));
+    assert!(lines.contains(&"multicache_entries{cache=\"thumbs \\\"small\\\"\"} 1"));
+    assert!(lines.contains(&"multicache_maxsize_bytes{cache=\"thumbs \\\"small\\\"\"} 100"));
+    assert!(lines.contains(&"multicache_hits_total{cache=\"images\"} 1"));
+    assert!(lines.contains(&"multicache_removals_total{cache=\"images\",cause=\"evicted\"} 1"));
+    assert!(lines.contains(&"multicache_evicted_bytes_total{cache=\"images\"} 100"));
+  }
+}
@@ -0,0 +1,384 @@
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use crate::clock::Clock;
+use crate::policy::{EvictionPolicy, Lru};
+use crate::{CacheStats, Lookup, MultiCache, OversizePolicy, PutError, RemovalCause, Weigher};
+
+/// A cache split into a number of independently locked shards so that threads working
+/// on different keys don't have to wait on each other. Keys are hashed into a shard
+/// and each shard starts out with an equal share of the total byte budget. Since keys
+/// don't always spread evenly calling `rebalance` from time to time moves budget to
+/// the shards that have been getting more data put in them.
+pub struct ShardedMultiCache<K,V,P=Lru<K>> {
+  shards: Vec<MultiCache<K,V,P>>,
+  demand: Vec<AtomicUsize>,
+  hasher: RandomState,
+  maxsize: AtomicUsize,
+}
+
+impl<K,V,P> fmt::Debug for ShardedMultiCache<K,V,P> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("ShardedMultiCache")
+      .field("shards", &self.shards)
+      .field("demand", &self.demand)
+      .field("hasher", &self.hasher)
+      .field("maxsize", &self.maxsize)
+      .finish()
+  }
+}
+
+impl<K,V> ShardedMultiCache<K,V> {
+  /// Create a new cache with a given number of shards which will at most hold a total
+  /// of bytesize in elements
+  pub fn new(bytesize: usize, shards: usize) -> ShardedMultiCache<K,V>
+  where K: Hash+Eq {
+    Self::with_policy(bytesize, shards, Lru::new)
+  }
+}
+
+impl<K,V,P> ShardedMultiCache<K,V,P> {
+  /// Create a new cache with a given number of shards which will at most hold a total
+  /// of bytesize in elements, each shard gets its own policy created by the given
+  /// function
+  pub fn with_policy<F>(bytesize: usize, shards: usize, mut policy: F) -> ShardedMultiCache<K,V,P>
+  where K: Hash+Eq, P: EvictionPolicy<K>, F: FnMut() -> P {
+    let shards = shards.max(1);
+    ShardedMultiCache {
+      shards: (0..shards).map(|i| {
+        MultiCache::with_policy(Self::share(bytesize, shards, i), policy())
+      }).collect(),
+      demand: (0..shards).map(|_| AtomicUsize::new(0)).collect(),
+      hasher: RandomState::new(),
+      maxsize: AtomicUsize::new(bytesize),
+    }
+  }
+
+  // Split a budget evenly with the rounding leftovers going to the first shards
+  fn share(bytesize: usize, shards: usize, i: usize) -> usize {
+    bytesize / shards + if i < bytesize % shards { 1 } else { 0 }
+  }
+
+  fn shard(&self, key: &K) -> usize
+  where K: Hash {
+    (self.hasher.hash_one(key) % self.shards.len() as u64) as usize
+  }
+
+  /// Set the time to live for elements added from now on in all shards
+  pub fn set_default_ttl(&self, ttl: Option<Duration>) {
+    for shard in self.shards.iter() {
+      shard.set_default_ttl(ttl);
+    }
+  }
+
+  /// Set how long elements can go without being read before they expire in all shards
+  pub fn set_idle_timeout(&self, idle: Option<Duration>) {
+    for shard in self.shards.iter() {
+      shard.set_idle_timeout(idle);
+    }
+  }
+
+  /// Set where the current time gets read from for all shards
+  pub fn set_clock(&self, clock: Arc<dyn Clock>) {
+    for shard in self.shards.iter() {
+      shard.set_clock(clock.clone());
+    }
+  }
+
+  /// Set a function to be called with every element that stops being in any of the
+  /// shards and the reason why
+  pub fn set_eviction_listener<F>(&self, listener: F)
+  where F: Fn(K, Arc<V>, usize, RemovalCause) + Send + Sync + 'static {
+    let listener = Arc::new(listener);
+    for shard in self.shards.iter() {
+      let listener = listener.clone();
+      shard.set_eviction_listener(move |key, val, bytes, cause| listener(key, val, bytes, cause));
+    }
+  }
+
+  /// Set how to work out the bytesize of elements put in with `put_weighed` in all shards
+  pub fn set_weigher<W>(&self, weigher: W)
+  where W: Weigher<K,V> + 'static {
+    let weigher = Arc::new(weigher);
+    for shard in self.shards.iter() {
+      let weigher = weigher.clone();
+      shard.set_weigher(move |key: &K, value: &V| weigher.weigh(key, value));
+    }
+  }
+
+  /// Set how keys show up in tracing events in all shards
+  #[cfg(feature = "tracing")]
+  pub fn set_key_formatter<F>(&self, formatter: F)
+  where F: Fn(&K) -> String + Send + Sync + 'static {
+    let formatter = Arc::new(formatter);
+    for shard in self.shards.iter() {
+      let formatter = formatter.clone();
+      shard.set_key_formatter(move |key: &K| formatter(key));
+    }
+  }
+
+  /// Set what happens in all shards to elements larger than the shard they go in
+  pub fn set_oversize_policy(&self, policy: OversizePolicy) {
+    for shard in self.shards.iter() {
+      shard.set_oversize_policy(policy);
+    }
+  }
+
+  /// Add a new element by key/value with a given bytesize to its shard
+  pub fn put(&self, key: K, value: V, bytes: usize)
+  where K: Hash+Eq, P: EvictionPolicy<K> {
+    self.put_arc(key, Arc::new(value), bytes)
+  }
+
+  /// Add a new element by key/Arc<value> with a given bytesize to its shard
+  pub fn put_arc(&self, key: K, value: Arc<V>, bytes: usize)
+  where K: Hash+Eq, P: EvictionPolicy<K> {
+    let shard = self.shard(&key);
+    self.demand[shard].fetch_add(bytes, Ordering::Relaxed);
+    self.shards[shard].put_arc(key, value, bytes)
+  }
+
+  /// Add a new element by key/value to its shard with its bytesize worked out by the
+  /// weigher, returning an error if there's no weigher or the oversize policy rejects it
+  pub fn put_weighed(&self, key: K, value: V) -> Result<Arc<V>,PutError>
+  where K: Hash+Eq, P: EvictionPolicy<K> {
+    let shard = self.shard(&key);
+    let bytes = self.shards[shard].weigh(&key, &value).ok_or(PutError::NoWeigher)?;
+    self.demand[shard].fetch_add(bytes, Ordering::Relaxed);
+    self.shards[shard].try_put(key, value, bytes)
+  }
+
+  /// Add a new element by key/value with a given bytesize to its shard, returning an
+  /// error if it's larger than the shard and the oversize policy rejects it
+  pub fn try_put(&self, key: K, value: V, bytes: usize) -> Result<Arc<V>,PutError>
+  where K: Hash+Eq, P: EvictionPolicy<K> {
+    let shard = self.shard(&key);
+    self.demand[shard].fetch_add(bytes, Ordering::Relaxed);
+    self.shards[shard].try_put(key, value, bytes)
+  }
+
+  /// Add a new element by key/value with a given bytesize to its shard that will expire
+  /// after a given amount of time
+  pub fn put_with_ttl(&self, key: K, value: V, bytes: usize, ttl: Duration)
+  where K: Hash+Eq, P: EvictionPolicy<K> {
+    let shard = self.shard(&key);
+    self.demand[shard].fetch_add(bytes, Ordering::Relaxed);
+    self.shards[shard].put_with_ttl(key, value, bytes, ttl)
+  }
+
+  /// Remember in its shard that a key doesn't exist, taking up a given bytesize until a
+  /// given amount of time passes
+  pub fn put_absent(&self, key: K, bytes: usize, ttl: Duration)
+  where K: Hash+Eq, P: EvictionPolicy<K> {
+    let shard = self.shard(&key);
+    self.demand[shard].fetch_add(bytes, Ordering::Relaxed);
+    self.shards[shard].put_absent(key, bytes, ttl)
+  }
+
+  /// Add a new element by key/value with a given bytesize to its shard that will be
+  /// fresh for a given amount of time and then stale for another one before it expires
+  pub fn put_with_stale(&self, key: K, value: V, bytes: usize, fresh: Duration, stale: Duration)
+  where K: Hash+Eq, P: EvictionPolicy<K> {
+    let shard = self.shard(&key);
+    self.demand[shard].fetch_add(bytes, Ordering::Relaxed);
+    self.shards[shard].put_with_stale(key, value, bytes, fresh, stale)
+  }
+
+  /// Get an element from the cache
+  pub fn get(&self, key: &K) -> Option<Arc<V>>
+  where K: Hash+Eq, P: EvictionPolicy<K> {
+    self.shards[self.shard(key)].get(key)
+  }
+
+  /// Get an element from the cache telling whether it's fresh or stale
+  pub fn lookup(&self, key: &K) -> Option<Lookup<V>>
+  where K: Hash+Eq, P: EvictionPolicy<K> {
+    self.shards[self.shard(key)].lookup(key)
+  }
+
+  /// Remove an element from the cache, returning it if it exists
+  pub fn remove(&self, key: &K) -> Option<Arc<V>>
+  where K: Hash+Eq, P: EvictionPolicy<K> {
+    self.shards[self.shard(key)].remove(key)
+  }
+
+  /// Check if a given key exists in the cache
+  pub fn contains_key(&self, key: &K) -> bool
+  where K: Hash+Eq, P: EvictionPolicy<K> {
+    self.shards[self.shard(key)].contains_key(key)
+  }
+
+  /// Remove all the elements that have expired in all shards
+  pub fn purge_expired(&self)
+  where K: Hash+Eq+Clone, P: EvictionPolicy<K> {
+    for shard in self.shards.iter() {
+      shard.purge_expired();
+    }
+  }
+
+  /// Remove all the elements from the cache
+  pub fn clear(&self)
+  where K: Hash+Eq, P: EvictionPolicy<K> {
+    for shard in self.shards.iter() {
+      shard.clear();
+    }
+  }
+
+  /// How many elements are in all the shards
+  pub fn len(&self) -> usize
+  where K: Hash+Eq {
+    self.shards.iter().map(|shard| shard.len()).sum()
+  }
+
+  /// Whether there are no elements in any of the shards
+  pub fn is_empty(&self) -> bool
+  where K: Hash+Eq {
+    self.shards.iter().all(|shard| shard.is_empty())
+  }
+
+  /// How many bytes the elements in all the shards take up
+  pub fn totalsize(&self) -> usize {
+    self.shards.iter().map(|shard| shard.totalsize()).sum()
+  }
+
+  /// How many bytes the cache can hold across all the shards
+  pub fn maxsize(&self) -> usize {
+    self.maxsize.load(Ordering::Relaxed)
+  }
+
+  /// Change how many bytes the cache can hold across all the shards. Each shard keeps
+  /// the same share of the total it had and the ones that shrunk evict right away.
+  pub fn set_max_bytes(&self, bytesize: usize)
+  where K: Hash+Eq, P: EvictionPolicy<K> {
+    let old = self.maxsize.swap(bytesize, Ordering::Relaxed);
+    let mut sizes: Vec<usize> = self.shards.iter().enumerate().map(|(i, shard)| {
+      match old {
+        0 => Self::share(bytesize, self.shards.len(), i),
+        _ => (shard.maxsize() as u128 * bytesize as u128 / old as u128) as usize,
+      }
+    }).collect();
+    // Whatever got lost to rounding goes to the largest shard
+    let assigned: usize = sizes.iter().sum();
+    let largest = (0..sizes.len()).max_by_key(|&i| sizes[i]).unwrap();
+    sizes[largest] += bytesize - assigned;
+    self.resize(&sizes);
+  }
+
+  /// The stats of all the shards added up
+  pub fn stats(&self) -> CacheStats {
+    self.shards.iter().map(|shard| shard.stats()).fold(CacheStats::default(), |a, b| a + b)
+  }
+
+  /// Start counting stats over from zero in all the shards
+  pub fn reset_stats(&self) {
+    for shard in self.shards.iter() {
+      shard.reset_stats();
+    }
+  }
+
+  /// Redistribute the byte budget between the shards. Half of it is always split evenly
+  /// and the other half goes to each shard in proportion to how many bytes were put in
+  /// it since the last rebalance. Shards that lose budget evict right away.
+  pub fn rebalance(&self)
+  where K: Hash+Eq, P: EvictionPolicy<K> {
+    let shards = self.shards.len();
+    let demand: Vec<usize> = self.demand.iter().map(|d| d.swap(0, Ordering::Relaxed)).collect();
+    let total: u128 = demand.iter().map(|&d| d as u128).sum();
+    if total == 0 {
+      return
+    }
+
+    let maxsize = self.maxsize();
+    let fixed = maxsize / 2;
+    let variable = maxsize - fixed;
+    let mut sizes: Vec<usize> = demand.iter().enumerate().map(|(i, &d)| {
+      Self::share(fixed, shards, i) + (variable as u128 * d as u128 / total) as usize
+    }).collect();
+    // Whatever got lost to rounding goes to the hungriest shard
+    let assigned: usize = sizes.iter().sum();
+    let hungriest = (0..shards).max_by_key(|&i| demand[i]).unwrap();
+    sizes[hungriest] += maxsize - assigned;
+    self.resize(&sizes);
+  }
+
+  // Shrink first so we never go over the total budget
+  fn resize(&self, sizes: &[usize])
+  where K: Hash+Eq, P: EvictionPolicy<K> {
+    for (shard, &size) in self.shards.iter().zip(sizes.iter()) {
+      if size < shard.maxsize() {
+        shard.set_max_bytes(size);
+      }
+    }
+    for (shard, &size) in self.shards.iter().zip(sizes.iter()) {
+      if size >= shard.maxsize() {
+        shard.set_max_bytes(size);
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::ShardedMultiCache;
+  use std::sync::Arc;
+  use std::thread;
+
+  #[test]
+  fn splits_budget() {
+    let cache: ShardedMultiCache<u32,u32> = ShardedMultiCache::new(1003, 4);
+
+    let sizes: Vec<usize> = cache.shards.iter().map(|s| s.maxsize()).collect();
+    assert_eq!(sizes, vec![251, 251, 251, 250]);
+  }
+
+  #[test]
+  fn gets_from_threads() {
+    let cache = Arc::new(ShardedMultiCache::new(10000, 8));
+
+    let threads: Vec<_> = (0..4).map(|t| {
+      let cache = cache.clone();
+      thread::spawn(move || {
+        for i in (t * 10)..(t * 10 + 10) {
+          cache.put(i, i, 10);
+          assert_eq!(cache.get(&i), Some(Arc::new(i)));
+        }
+      })
+    }).collect();
+    for thread in threads {
+      thread.join().unwrap();
+    }
+
+    for i in 0..40 {
+      assert_eq!(cache.get(&i), Some(Arc::new(i)));
+    }
+  }
+
+  #[test]
+  fn rebalances_to_hot_shard() {
+    let cache = ShardedMultiCache::new(400, 4);
+    let hot: Vec<u32> = (0..1000).filter(|k| cache.shard(k) == 0).take(6).collect();
+
+    for k in hot.iter() {
+      cache.put(*k, *k, 50);
+    }
+    // Only fits two of the entries before rebalancing
+    assert_eq!(hot.iter().filter(|k| cache.contains_key(k)).count(), 2);
+
+    cache.rebalance();
+    assert_eq!(cache.shards[0].maxsize(), 50 + 200);
+    assert_eq!(cache.shards[1].maxsize(), 50);
+    for k in hot.iter() {
+      cache.put(*k, *k, 50);
+    }
+    assert_eq!(hot.iter().filter(|k| cache.contains_key(k)).count(), 5);
+
+    // Resizing keeps the split
+    cache.set_max_bytes(800);
+    assert_eq!(cache.shards[0].maxsize(), 500);
+    assert_eq!(cache.shards[1].maxsize(), 100);
+    assert_eq!(cache.maxsize(), 800);
+  }
+}
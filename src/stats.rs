@@ -0,0 +1,194 @@
+use std::ops::Add;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use crate::RemovalCause;
+
+/// A snapshot of how a cache has been doing since it was created or its stats were
+/// last reset
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+  /// Reads that found a value
+  pub hits: u64,
+  /// Reads that didn't find a value, including ones of keys marked as absent
+  pub misses: u64,
+  /// Elements put in
+  pub inserts: u64,
+  /// Elements overwritten by a put with the same key
+  pub replacements: u64,
+  /// Elements evicted to make room for others
+  pub evictions: u64,
+  /// Bytes taken up by the evicted elements
+  pub evicted_bytes: u64,
+  /// Elements removed by calling remove
+  pub removals: u64,
+  /// Elements that expired
+  pub expirations: u64,
+  /// Elements removed by calling clear
+  pub clears: u64,
+  /// The most bytes the cache has held at once
+  pub peak_size: usize,
+}
+
+impl CacheStats {
+  /// Total number of reads
+  pub fn requests(&self) -> u64 {
+    self.hits + self.misses
+  }
+
+  /// Share of the reads that found a value, 1.0 if there were none
+  pub fn hit_ratio(&self) -> f64 {
+    match self.requests() {
+      0 => 1.0,
+      requests => self.hits as f64 / requests as f64,
+    }
+  }
+
+  /// Share of the reads that didn't find a value, 0.0 if there were none
+  pub fn miss_ratio(&self) -> f64 {
+    1.0 - self.hit_ratio()
+  }
+}
+
+// Adding up the stats of several caches, the peak is the sum of their peaks
+impl Add for CacheStats {
+  type Output = CacheStats;
+
+  fn add(self, other: CacheStats) -> CacheStats {
+    CacheStats {
+      hits: self.hits + other.hits,
+      misses: self.misses + other.misses,
+      inserts: self.inserts + other.inserts,
+      replacements: self.replacements + other.replacements,
+      evictions: self.evictions + other.evictions,
+      evicted_bytes: self.evicted_bytes + other.evicted_bytes,
+      removals: self.removals + other.removals,
+      expirations: self.expirations + other.expirations,
+      clears: self.clears + other.clears,
+      peak_size: self.peak_size + other.peak_size,
+    }
+  }
+}
+
+// The live counters, only ever touched with relaxed atomics so keeping them doesn't
+// make the cache hold its lock any longer
+#[derive(Debug, Default)]
+pub(crate) struct StatsCounter {
+  hits: AtomicU64,
+  misses: AtomicU64,
+  inserts: AtomicU64,
+  replacements: AtomicU64,
+  evictions: AtomicU64,
+  evicted_bytes: AtomicU64,
+  removals: AtomicU64,
+  expirations: AtomicU64,
+  clears: AtomicU64,
+  peak_size: AtomicUsize,
+}
+
+impl StatsCounter {
+  // A read that found a value or didn't
+  pub(crate) fn read(&self, found: bool) {
+    let counter = if found { &self.hits } else { &self.misses };
+    counter.fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub(crate) fn insert(&self, totalsize: usize) {
+    self.inserts.fetch_add(1, Ordering::Relaxed);
+    self.peak_size.fetch_max(totalsize, Ordering::Relaxed);
+  }
+
+  pub(crate) fn removal(&self, bytes: usize, cause: RemovalCause) {
+    let counter = match cause {
+      RemovalCause::Evicted => {
+        self.evicted_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+        &self.evictions
+      },
+      RemovalCause::Replaced => &self.replacements,
+      RemovalCause::Explicit => &self.removals,
+      RemovalCause::Expired => &self.expirations,
+      RemovalCause::Cleared => &self.clears,
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub(crate) fn snapshot(&self) -> CacheStats {
+    CacheStats {
+      hits: self.hits.load(Ordering::Relaxed),
+      misses: self.misses.load(Ordering::Relaxed),
+      inserts: self.inserts.load(Ordering::Relaxed),
+      replacements: self.replacements.load(Ordering::Relaxed),
+      evictions: self.evictions.load(Ordering::Relaxed),
+      evicted_bytes: self.evicted_bytes.load(Ordering::Relaxed),
+      removals: self.removals.load(Ordering::Relaxed),
+      expirations: self.expirations.load(Ordering::Relaxed),
+      clears: self.clears.load(Ordering::Relaxed),
+      peak_size: self.peak_size.load(Ordering::Relaxed),
+    }
+  }
+
+  // The peak starts over from what the cache holds right now
+  pub(crate) fn reset(&self, totalsize: usize) {
+    for counter in [&self.hits, &self.misses, &self.inserts, &self.replacements,
+                    &self.evictions, &self.evicted_bytes, &self.removals,
+                    &self.expirations, &self.clears] {
+      counter.store(0, Ordering::Relaxed);
+    }
+    self.peak_size.store(totalsize, Ordering::Relaxed);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::{LoadingCache, MultiCache};
+  use super::CacheStats;
+  use std::sync::Arc;
+  use std::time::Duration;
+
+  #[test]
+  fn counts() {
+    let cache = MultiCache::new(200);
+
+    cache.put(0, 0, 100);
+    cache.put(0, 1, 100);
+    cache.put(1, 1, 100);
+    cache.put(2, 2, 100);
+    cache.put_with_ttl(3, 3, 50, Duration::from_secs(0));
+    cache.get(&0);
+    cache.get(&2);
+    cache.get(&3);
+    cache.remove(&2);
+
+    assert_eq!(cache.stats(), CacheStats {
+      hits: 1,
+      misses: 2,
+      inserts: 5,
+      replacements: 1,
+      evictions: 2,
+      evicted_bytes: 200,
+      removals: 1,
+      expirations: 1,
+      clears: 0,
+      peak_size: 200,
+    });
+    assert!((cache.stats().hit_ratio() - 1.0 / 3.0).abs() < 1e-9);
+
+    cache.reset_stats();
+    assert_eq!(cache.stats(), CacheStats::default());
+    assert_eq!(cache.stats().hit_ratio(), 1.0);
+  }
+
+  #[test]
+  fn counts_each_read_once() {
+    let cache = Arc::new(MultiCache::new(1000));
+
+    cache.put_absent(0, 10, Duration::from_secs(60));
+    assert_eq!(cache.get(&0), None);
+    assert_eq!(cache.get_or_insert_with(1, || (1, 100)), Some(Arc::new(1)));
+    let loading = LoadingCache::new(cache.clone(), Duration::from_secs(60), |key| (*key, 100));
+    assert_eq!(loading.get(&2), Some(Arc::new(2)));
+    assert_eq!((cache.stats().hits, cache.stats().misses), (0, 3));
+
+    assert_eq!(loading.get(&2), Some(Arc::new(2)));
+    assert_eq!(cache.get_or_insert_with(1, || (2, 100)), Some(Arc::new(1)));
+    assert_eq!((cache.stats().hits, cache.stats().misses), (2, 3));
+  }
+}
@@ -0,0 +1,63 @@
+/// Works out the bytesize of elements put in with `put_weighed`. Any
+/// `Fn(&K, &V) -> usize` closure is a weigher, `HeapSizeWeigher` counts all the memory
+/// taken up by keys and values that implement `HeapSize` and `ValueSizeWeigher` only
+/// the one taken up by values.
+pub trait Weigher<K,V>: Send + Sync {
+  fn weigh(&self, key: &K, value: &V) -> usize;
+}
+
+impl<K,V,F> Weigher<K,V> for F
+where F: Fn(&K, &V) -> usize + Send + Sync {
+  fn weigh(&self, key: &K, value: &V) -> usize {
+    self(key, value)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::{HeapSizeWeigher, MultiCache, PutError, ValueSizeWeigher};
+  use std::mem;
+  use std::sync::Arc;
+
+  #[test]
+  fn weighs_by_heap_size() {
+    let cache = MultiCache::with_weigher(1000, HeapSizeWeigher);
+
+    cache.put_weighed(0u32, Vec::<u64>::with_capacity(50)).unwrap();
+    assert_eq!(cache.totalsize(), 4 + mem::size_of::<Vec<u64>>() + 400);
+    cache.put_weighed(1, Vec::<u64>::with_capacity(100)).unwrap();
+    assert_eq!(cache.get(&0), None);
+    assert!(cache.contains_key(&1));
+  }
+
+  #[test]
+  fn weighs_values() {
+    // Keys don't need to implement HeapSize
+    #[derive(Hash, PartialEq, Eq)]
+    struct Key(u32);
+    let cache = MultiCache::with_weigher(1000, ValueSizeWeigher);
+
+    cache.put_weighed(Key(0), Box::<[u8]>::from(vec![0; 100])).unwrap();
+    assert_eq!(cache.totalsize(), mem::size_of::<Box<[u8]>>() + 100);
+    cache.put_weighed(Key(1), vec![0u8; 900].into_boxed_slice()).unwrap();
+    assert!(!cache.contains_key(&Key(0)));
+    assert!(cache.contains_key(&Key(1)));
+  }
+
+  #[test]
+  fn weighs_with_closure() {
+    let cache = MultiCache::with_weigher(100, |key: &u32, value: &String| *key as usize + value.len());
+
+    cache.put_weighed(10, "abc".to_string()).unwrap();
+    assert_eq!(cache.totalsize(), 13);
+    assert_eq!(cache.get(&10), Some(Arc::new("abc".to_string())));
+  }
+
+  #[test]
+  fn needs_weigher() {
+    let cache = MultiCache::new(100);
+
+    assert_eq!(cache.put_weighed(0, 0), Err(PutError::NoWeigher));
+    assert!(cache.is_empty());
+  }
+}